@@ -0,0 +1,254 @@
+use crate::config::{self, Api, Secret};
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use reqwest::{
+    header::{self, HeaderValue},
+    Method, RequestBuilder, StatusCode,
+};
+use ring::{
+    rand::SystemRandom,
+    signature::{RsaKeyPair, RSA_PKCS1_SHA256},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    sync::{Arc, Mutex},
+};
+use tracing::info;
+
+/// The longest description GitHub accepts for a deployment status
+const MAX_DESCRIPTION: usize = 140;
+
+/// A client for the GitHub REST API
+#[derive(Clone, Debug)]
+pub struct Client {
+    http: reqwest::Client,
+    url: String,
+    token: Option<Secret>,
+    app: Option<Arc<App>>,
+    /// Installation tokens by installation ID
+    tokens: Arc<Mutex<HashMap<u64, Token>>>,
+}
+
+/// A GitHub App used to mint installation tokens
+struct App {
+    id: u64,
+    key: RsaKeyPair,
+}
+
+impl fmt::Debug for App {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("App").field("id", &self.id).finish()
+    }
+}
+
+/// An installation token and when it expires
+#[derive(Clone, Debug, Deserialize)]
+struct Token {
+    token: Secret,
+    expires_at: DateTime<Utc>,
+}
+
+impl Client {
+    /// Create a new client from the configuration
+    pub fn new(config: &Api) -> Result<Self> {
+        let http = reqwest::Client::builder()
+            .user_agent(concat!("autodeploy/", env!("CARGO_PKG_VERSION")))
+            .build()?;
+
+        let app = match &config.app {
+            Some(app) => Some(Arc::new(App::load(app)?)),
+            None => None,
+        };
+
+        Ok(Self {
+            http,
+            url: config.url.trim_end_matches('/').to_string(),
+            token: config.token.clone(),
+            app,
+            tokens: Arc::default(),
+        })
+    }
+
+    /// Build a request to the API
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let request = self
+            .http
+            .request(method, format!("{}{}", self.url, path))
+            .header(header::ACCEPT, "application/vnd.github.v3+json");
+
+        match &self.token {
+            Some(token) => request.header(header::AUTHORIZATION, authorization("token", token)),
+            None => request,
+        }
+    }
+
+    /// Whether the client can mint installation tokens
+    pub fn has_app(&self) -> bool {
+        self.app.is_some()
+    }
+
+    /// Get a token for fetching the installation's repositories, reusing
+    /// the previous one until it is about to expire
+    pub async fn installation_token(&self, installation: u64) -> Result<Secret> {
+        let app = self.app.as_ref().context("no GitHub App configured")?;
+
+        let cached = self.tokens.lock().unwrap().get(&installation).cloned();
+        if let Some(token) = cached {
+            if token.expires_at - Duration::minutes(1) > Utc::now() {
+                return Ok(token.token);
+            }
+        }
+
+        info!(installation, "creating installation token");
+        let jwt = Secret::from(app.jwt()?);
+        let path = format!("/app/installations/{}/access_tokens", installation);
+        let token: Token = self
+            .http
+            .post(format!("{}{}", self.url, path))
+            .header(header::ACCEPT, "application/vnd.github.v3+json")
+            .header(header::AUTHORIZATION, authorization("Bearer", &jwt))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        self.tokens
+            .lock()
+            .unwrap()
+            .insert(installation, token.clone());
+        Ok(token.token)
+    }
+
+    /// Report the status of a deployment
+    pub async fn create_deployment_status(
+        &self,
+        repository: &str,
+        id: u64,
+        state: DeploymentState,
+        description: &str,
+    ) -> Result<()> {
+        #[derive(Serialize)]
+        struct Status<'a> {
+            state: DeploymentState,
+            description: &'a str,
+        }
+
+        // GitHub rejects longer descriptions
+        let description = if description.chars().count() > MAX_DESCRIPTION {
+            let truncated = description.chars().take(MAX_DESCRIPTION - 1);
+            truncated.chain(Some('…')).collect()
+        } else {
+            description.to_string()
+        };
+
+        let path = format!("/repos/{}/deployments/{}/statuses", repository, id);
+        self.request(Method::POST, &path)
+            .json(&Status {
+                state,
+                description: &description,
+            })
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+
+    /// Check if the user is an active member of the team
+    pub async fn is_team_member(&self, org: &str, team: &str, login: &str) -> Result<bool> {
+        #[derive(Deserialize)]
+        struct Membership {
+            state: String,
+        }
+
+        let path = format!("/orgs/{}/teams/{}/memberships/{}", org, team, login);
+        let response = self.request(Method::GET, &path).send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+
+        let membership: Membership = response.error_for_status()?.json().await?;
+        Ok(membership.state == "active")
+    }
+}
+
+/// Build an authorization header that is hidden from debug output
+fn authorization(scheme: &str, credential: &Secret) -> HeaderValue {
+    let mut value = HeaderValue::from_str(&format!("{} {}", scheme, credential.expose()))
+        .expect("credentials are valid header values");
+    value.set_sensitive(true);
+    value
+}
+
+impl App {
+    /// Load the App's private key, either PKCS#1 or PKCS#8 PEM encoded
+    fn load(config: &config::App) -> Result<Self> {
+        let pem = fs::read_to_string(&config.private_key)
+            .context("failed to read GitHub App private key")?;
+        let encoded = pem
+            .lines()
+            .filter(|l| !l.starts_with("-----"))
+            .collect::<String>();
+        let der = base64::decode(encoded).context("invalid GitHub App private key")?;
+
+        let key = if pem.contains("BEGIN RSA PRIVATE KEY") {
+            RsaKeyPair::from_der(&der)
+        } else {
+            RsaKeyPair::from_pkcs8(&der)
+        }
+        .map_err(|e| anyhow!("invalid GitHub App private key: {}", e))?;
+
+        Ok(Self { id: config.id, key })
+    }
+
+    /// Create a short-lived JSON web token authenticating as the App
+    fn jwt(&self) -> Result<String> {
+        let encode = |data: &[u8]| base64::encode_config(data, base64::URL_SAFE_NO_PAD);
+
+        // Backdate the token to allow for clock drift
+        let now = Utc::now().timestamp();
+        let header = encode(br#"{"alg":"RS256","typ":"JWT"}"#);
+        let claims = json!({ "iat": now - 60, "exp": now + 9 * 60, "iss": self.id });
+        let message = format!("{}.{}", header, encode(claims.to_string().as_bytes()));
+
+        let mut signature = vec![0; self.key.public_modulus_len()];
+        self.key
+            .sign(
+                &RSA_PKCS1_SHA256,
+                &SystemRandom::new(),
+                message.as_bytes(),
+                &mut signature,
+            )
+            .map_err(|_| anyhow!("failed to sign GitHub App token"))?;
+
+        Ok(format!("{}.{}", message, encode(&signature)))
+    }
+}
+
+/// The possible states of a deployment
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeploymentState {
+    Error,
+    Failure,
+    Inactive,
+    InProgress,
+    Success,
+}
+
+impl DeploymentState {
+    /// A short description of the state
+    pub fn description(&self) -> &'static str {
+        match self {
+            Self::Error => "Failed to prepare the deploy",
+            Self::Failure => "Deploy failed",
+            Self::Inactive => "Superseded by a newer deploy",
+            Self::InProgress => "Deploying",
+            Self::Success => "Deploy succeeded",
+        }
+    }
+}
@@ -23,4 +23,57 @@ pub struct Args {
     /// environment variable RUST_LOG can also be used.
     #[structopt(short, long, env = "RUST_LOG")]
     pub log_level: Option<String>,
+
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    /// Manage SSH deploy keys
+    ///
+    /// Keys are stored in the keys directory from the credentials configuration
+    /// and used automatically when fetching the repository.
+    Keys(Keys),
+
+    /// Roll a checkout back to an earlier deploy
+    ///
+    /// Asks the running server to redeploy the previous successful deploy, or
+    /// the one given, signing the request with the webhook secret.
+    Rollback {
+        /// The repository in the format <owner>/<repo>
+        repository: String,
+
+        /// The checkout to roll back, such as branch-<name>, tag-<name>, env-<name>,
+        /// pr-<number> or releases
+        #[structopt(short, long)]
+        target: String,
+
+        /// The commit or GitHub deployment ID to roll back to
+        #[structopt(long)]
+        to: Option<String>,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+pub enum Keys {
+    /// Generate a deploy key for a repository
+    ///
+    /// Prints the public key to add to the repository's deploy keys on GitHub.
+    Generate {
+        /// The repository in the format <owner>/<repo>
+        repository: String,
+    },
+
+    /// List the repositories with deploy keys
+    List,
+
+    /// Replace the deploy key for a repository
+    ///
+    /// The previous key is kept with a `.old` extension until it is removed
+    /// from GitHub.
+    Rotate {
+        /// The repository in the format <owner>/<repo>
+        repository: String,
+    },
 }
@@ -1,45 +1,573 @@
-use anyhow::Result;
-use serde::Deserialize;
+use crate::github::{Github, ReleaseAction};
+use anyhow::{bail, Result};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
 use std::{
+    collections::HashMap,
+    fmt,
     net::SocketAddr,
     path::{Path, PathBuf},
+    str::FromStr,
 };
 use tokio::fs;
 
 /// Parse the configuration from a given file
 pub async fn parse<P: AsRef<Path>>(path: P) -> Result<Config> {
     let raw = fs::read(path).await?;
-    Ok(toml::from_slice(&raw)?)
+    let mut config: Config = toml::from_slice(&raw)?;
+    config.server.resolve_pools()?;
+    config.validate()?;
+    Ok(config)
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
     pub server: Server,
+    #[serde(default)]
+    pub github: Api,
+    #[serde(default)]
+    pub credentials: Credentials,
+    #[serde(default)]
+    pub git: Git,
     pub events: Vec<Event>,
 }
 
+impl Config {
+    /// Ensure every event is routed to a worker pool that exists
+    fn validate(&self) -> Result<()> {
+        for (name, &size) in &self.server.pools {
+            if size == 0 {
+                bail!("worker pool {:?} must have at least one worker", name);
+            }
+        }
+        if !self.server.pools.contains_key(DEFAULT_POOL) {
+            bail!("the {:?} worker pool must be configured", DEFAULT_POOL);
+        }
+
+        // Previews share the production checkout otherwise, which is removed on teardown
+        let previews = self.events.is_empty()
+            || self
+                .events
+                .iter()
+                .any(|e| matches!(e.action, Action::PullRequest { .. }));
+        if previews && !self.server.checkout.contains("{target}") {
+            bail!(
+                "the checkout must contain {} when pull requests are previewed",
+                "{target}"
+            );
+        }
+
+        for event in &self.events {
+            if let Some(pool) = &event.pool {
+                if !self.server.pools.contains_key(pool) {
+                    bail!("unknown worker pool {:?}", pool);
+                }
+            }
+            if event.releases == Some(0) {
+                bail!("at least one release must be kept");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// How to connect to the GitHub API
+#[derive(Debug, Deserialize)]
+pub struct Api {
+    #[serde(default = "default_api_url")]
+    pub url: String,
+    pub token: Option<Secret>,
+    pub app: Option<App>,
+}
+
+impl Default for Api {
+    fn default() -> Self {
+        Self {
+            url: default_api_url(),
+            token: None,
+            app: None,
+        }
+    }
+}
+
+/// A GitHub App that fetches repositories using installation tokens
+#[derive(Debug, Deserialize)]
+pub struct App {
+    pub id: u64,
+    /// The path to the App's PEM encoded private key
+    pub private_key: PathBuf,
+}
+
+/// A value that is redacted when debug printed, such as a token
+#[derive(Clone, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    /// Get the actual value
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[redacted]")
+    }
+}
+
+fn default_api_url() -> String {
+    "https://api.github.com".into()
+}
+
+/// How to authenticate when fetching repositories
+#[derive(Clone, Debug, Deserialize)]
+pub struct Credentials {
+    /// The file to check the host keys of SSH remotes against
+    pub known_hosts: Option<PathBuf>,
+    /// Where deploy keys generated by `autodeploy keys` are stored
+    #[serde(default = "default_keys")]
+    pub keys: PathBuf,
+    /// Used by repositories without their own credentials
+    #[serde(flatten)]
+    pub default: RepositoryCredentials,
+    #[serde(default)]
+    pub repositories: HashMap<String, RepositoryCredentials>,
+}
+
+impl Default for Credentials {
+    fn default() -> Self {
+        Self {
+            known_hosts: None,
+            keys: default_keys(),
+            default: RepositoryCredentials::default(),
+            repositories: HashMap::new(),
+        }
+    }
+}
+
+fn default_keys() -> PathBuf {
+    "keys".into()
+}
+
+impl Credentials {
+    /// Get the credentials for a repository, falling back to its generated
+    /// deploy key and then the defaults
+    pub fn get(&self, repository: &str) -> RepositoryCredentials {
+        let own = self.repositories.get(repository);
+        let generated = Some(self.key_path(repository)).filter(|p| p.exists());
+        RepositoryCredentials {
+            ssh_key: own
+                .and_then(|c| c.ssh_key.clone())
+                .or(generated)
+                .or_else(|| self.default.ssh_key.clone()),
+            use_ssh_url: own.and_then(|c| c.use_ssh_url).or(self.default.use_ssh_url),
+            token: own
+                .and_then(|c| c.token.clone())
+                .or_else(|| self.default.token.clone()),
+        }
+    }
+
+    /// Get the path to the generated deploy key for a repository
+    pub fn key_path(&self, repository: &str) -> PathBuf {
+        self.keys.join(repository.replace('/', "__"))
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct RepositoryCredentials {
+    /// The private key for SSH remotes
+    pub ssh_key: Option<PathBuf>,
+    /// Whether to fetch using the SSH URL instead of the HTTPS one
+    pub use_ssh_url: Option<bool>,
+    /// A token for HTTPS remotes, used instead of the GitHub App
+    pub token: Option<Secret>,
+}
+
+/// How the local copies of repositories are updated
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Git {
+    /// Used by repositories without their own options
+    #[serde(flatten)]
+    pub default: RepositoryGit,
+    #[serde(default)]
+    pub repositories: HashMap<String, RepositoryGit>,
+}
+
+impl Git {
+    /// Get the options for a repository, falling back to the defaults
+    pub fn get(&self, repository: &str) -> RepositoryGit {
+        let own = self.repositories.get(repository);
+        RepositoryGit {
+            strategy: own.and_then(|g| g.strategy).or(self.default.strategy),
+            local_changes: own
+                .and_then(|g| g.local_changes)
+                .or(self.default.local_changes),
+            untracked: own.and_then(|g| g.untracked).or(self.default.untracked),
+            depth: own.and_then(|g| g.depth).or(self.default.depth),
+            tags: own.and_then(|g| g.tags).or(self.default.tags),
+            submodules: own
+                .and_then(|g| g.submodules.clone())
+                .or_else(|| self.default.submodules.clone()),
+            lfs: own.and_then(|g| g.lfs).or(self.default.lfs),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct RepositoryGit {
+    /// How the checkout is moved to the deployed commit
+    pub strategy: Option<Strategy>,
+    /// What to do with files changed in the checkout outside of deploys
+    pub local_changes: Option<LocalChanges>,
+    /// Whether untracked files count as local changes, left alone if not
+    pub untracked: Option<bool>,
+    /// How many commits of history the first fetch downloads, all if omitted
+    pub depth: Option<u32>,
+    /// Which tags are downloaded along with the deployed reference
+    pub tags: Option<Tags>,
+    /// The paths of the submodules to update, all of them if omitted
+    pub submodules: Option<Globs>,
+    /// Whether to replace LFS pointers with the objects they refer to
+    pub lfs: Option<bool>,
+}
+
+impl RepositoryGit {
+    /// Whether the submodule at the path should be updated
+    pub fn updates_submodule(&self, path: &str) -> bool {
+        match &self.submodules {
+            Some(globs) => globs.is_match(path),
+            None => true,
+        }
+    }
+}
+
+/// How the checkout is moved to the deployed commit
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strategy {
+    /// Discard anything not in the deployed commit
+    #[default]
+    Reset,
+    /// Fail if the deployed commit does not descend from the checkout
+    FastForward,
+    /// Merge the deployed commit into the checkout, failing on conflicts
+    Merge,
+}
+
+/// Which tags are downloaded along with the deployed reference
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Tags {
+    /// Every tag on the remote
+    #[default]
+    All,
+    /// No tags at all
+    None,
+    /// Only the tag being deployed
+    Reference,
+}
+
+/// What to do with files changed in the checkout outside of deploys
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LocalChanges {
+    /// Fail the deploy, leaving the changes in place
+    Abort,
+    /// Save the changes to a timestamped reference before deploying
+    Stash,
+    /// Throw the changes away
+    #[default]
+    Discard,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Server {
     pub address: SocketAddr,
     pub log: String,
     pub repositories: PathBuf,
     pub secret: String,
-    pub workers: u32,
+    /// The number of workers in each named pool
+    #[serde(default)]
+    pub pools: HashMap<String, u32>,
+    /// The number of workers in the default pool, from before pools existed
+    workers: Option<u32>,
+    #[serde(default = "default_skip_markers")]
+    pub skip_markers: Vec<String>,
+    #[serde(default)]
+    pub force_deploy: Vec<String>,
+    #[serde(default = "default_checkout")]
+    pub checkout: String,
+    /// How long to wait in seconds for newer deploys of the same checkout
+    /// before queueing a deploy
+    #[serde(default)]
+    pub debounce: u64,
+    #[serde(default)]
+    pub journal: Journal,
+}
+
+/// Where queued deploys are recorded so they survive restarts
+#[derive(Debug, Deserialize)]
+pub struct Journal {
+    #[serde(default = "default_journal_path")]
+    pub path: PathBuf,
+    /// How many times to retry a deploy interrupted by a restart
+    #[serde(default)]
+    pub retries: u32,
+}
+
+impl Default for Journal {
+    fn default() -> Self {
+        Self {
+            path: default_journal_path(),
+            retries: 0,
+        }
+    }
+}
+
+fn default_journal_path() -> PathBuf {
+    "journal.jsonl".into()
+}
+
+/// What a checkout deploys. Each kind is prefixed in the checkout's folder
+/// so a branch, tag and environment with the same name stay apart.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Target {
+    Branch(String),
+    Tag(String),
+    Environment(String),
+    PullRequest(u64),
+    /// Releases deployed without an environment
+    Releases,
+}
+
+impl Target {
+    /// Get the target for a reference, where anything other than a tag is a branch
+    pub fn reference(reference: &str) -> Target {
+        match reference.strip_prefix("refs/tags/") {
+            Some(tag) => Target::Tag(tag.to_string()),
+            None => Target::Branch(reference.trim_start_matches("refs/heads/").to_string()),
+        }
+    }
+
+    /// Get the name of the checkout's folder for the target
+    fn folder(&self) -> Result<String> {
+        match self {
+            Target::Branch(name) | Target::Tag(name) | Target::Environment(name)
+                if matches!(name.as_str(), "" | "." | "..") =>
+            {
+                bail!("invalid checkout target {:?}", name)
+            }
+            target => Ok(target.to_string().replace(['/', '\\'], "__")),
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Branch(name) => write!(f, "branch-{}", name),
+            Target::Tag(name) => write!(f, "tag-{}", name),
+            Target::Environment(name) => write!(f, "env-{}", name),
+            Target::PullRequest(number) => write!(f, "pr-{}", number),
+            Target::Releases => f.write_str("releases"),
+        }
+    }
+}
+
+impl FromStr for Target {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "releases" {
+            return Ok(Target::Releases);
+        }
+
+        let target = match s.split_once('-') {
+            Some(("branch", name)) => Target::Branch(name.to_string()),
+            Some(("tag", name)) => Target::Tag(name.to_string()),
+            Some(("env", name)) => Target::Environment(name.to_string()),
+            Some(("pr", number)) => Target::PullRequest(number.parse()?),
+            _ => bail!(
+                "target must be one of branch-<name>, tag-<name>, env-<name>, pr-<number> or releases"
+            ),
+        };
+        Ok(target)
+    }
+}
+
+impl Server {
+    /// Size the default pool from the deprecated `workers` setting, or give
+    /// it a single worker if no pools are configured
+    fn resolve_pools(&mut self) -> Result<()> {
+        match self.workers.take() {
+            Some(_) if self.pools.contains_key(DEFAULT_POOL) => bail!(
+                "workers cannot be set along with the {:?} worker pool",
+                DEFAULT_POOL
+            ),
+            Some(workers) => {
+                self.pools.insert(DEFAULT_POOL.to_string(), workers);
+            }
+            None if self.pools.is_empty() => {
+                self.pools.insert(DEFAULT_POOL.to_string(), 1);
+            }
+            None => {}
+        }
+
+        Ok(())
+    }
+
+    /// Get the path to the local copy of the repository for a target using the
+    /// checkout template. The template can contain `{owner}`, `{name}`,
+    /// `{repository}` (as `<owner>__<name>`) and `{target}`.
+    pub fn checkout_path(&self, repository: &str, target: &Target) -> Result<PathBuf> {
+        let (owner, name) = repository.split_once('/').unwrap_or(("", repository));
+        let folder = self
+            .checkout
+            .replace("{owner}", owner)
+            .replace("{name}", name)
+            .replace("{repository}", &repository.replace('/', "__"))
+            .replace("{target}", &target.folder()?);
+
+        Ok(self.repositories.join(folder))
+    }
+
+    /// Get the markers that prevent a commit in the repository from being deployed
+    pub fn skip_markers(&self, repository: &str) -> &[String] {
+        if self.force_deploy.iter().any(|r| r == repository) {
+            &[]
+        } else {
+            &self.skip_markers
+        }
+    }
+}
+
+/// The pool used by events that don't choose one
+pub const DEFAULT_POOL: &str = "default";
+
+fn default_checkout() -> String {
+    "{repository}@{target}".into()
+}
+
+fn default_skip_markers() -> Vec<String> {
+    vec!["[skip deploy]".into(), "[no deploy]".into()]
+}
+
+/// Check if the commit message contains any of the markers
+pub fn skipped(message: &str, markers: &[String]) -> bool {
+    markers.iter().any(|m| message.contains(m.as_str()))
 }
 
 #[derive(Debug, Deserialize)]
 #[serde(tag = "action", rename_all = "lowercase")]
 pub enum Action {
-    Push { branch: String },
-    Release,
+    Push {
+        branch: String,
+    },
+    Release {
+        #[serde(default = "default_release_actions")]
+        actions: Vec<ReleaseAction>,
+        #[serde(default)]
+        prerelease: Filter,
+        #[serde(default = "default_draft_filter")]
+        draft: Filter,
+        #[serde(default)]
+        revert: bool,
+    },
+    #[serde(rename = "pull_request")]
+    PullRequest {
+        #[serde(default)]
+        forks: bool,
+    },
+    Deployment {
+        #[serde(default)]
+        environments: Vec<String>,
+    },
+    #[serde(rename = "repository_dispatch")]
+    Dispatch {
+        event_type: String,
+    },
 }
 
 impl Action {
-    /// Checks that the branch is allowed on a push
-    pub fn matches(&self, branch: Option<&str>) -> bool {
+    /// Checks that the webhook is allowed to trigger the action
+    pub fn matches(&self, body: &Github) -> bool {
+        match (self, body) {
+            (Self::Push { branch }, Github::Push { reference, .. }) => {
+                branch == reference.trim_start_matches("refs/heads/")
+            }
+            (
+                Self::Release {
+                    actions,
+                    prerelease,
+                    draft,
+                    revert,
+                },
+                Github::Release {
+                    action, release, ..
+                },
+            ) => {
+                let triggered = actions.contains(action) || (*revert && action.is_removal());
+                triggered && prerelease.matches(release.prerelease) && draft.matches(release.draft)
+            }
+            (Self::PullRequest { .. }, Github::PullRequest { .. }) => true,
+            (Self::Deployment { environments }, Github::Deployment { deployment, .. }) => {
+                environments.is_empty() || environments.contains(&deployment.environment)
+            }
+            (
+                Self::Dispatch { event_type },
+                Github::RepositoryDispatch {
+                    event_type: received,
+                    ..
+                },
+            ) => event_type == received,
+            _ => false,
+        }
+    }
+
+    /// Whether pull requests from forks can be deployed
+    pub fn allows_forks(&self) -> bool {
+        matches!(self, Self::PullRequest { forks: true })
+    }
+
+    /// Whether removing the deployed release should revert to the previous one
+    pub fn reverts(&self) -> bool {
+        matches!(self, Self::Release { revert: true, .. })
+    }
+}
+
+fn default_release_actions() -> Vec<ReleaseAction> {
+    vec![ReleaseAction::Released]
+}
+
+fn default_draft_filter() -> Filter {
+    Filter::Exclude
+}
+
+/// How releases with a given flag should be treated
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Filter {
+    #[default]
+    Include,
+    Exclude,
+    Only,
+}
+
+impl Filter {
+    /// Checks that the flag's value is allowed
+    pub fn matches(&self, flag: bool) -> bool {
         match self {
-            Self::Push { branch: b } => b == branch.unwrap_or_default(),
-            Self::Release => true,
+            Self::Include => true,
+            Self::Exclude => !flag,
+            Self::Only => flag,
         }
     }
 }
@@ -64,18 +592,179 @@ impl Mode {
     }
 }
 
+/// The checks that must pass before a push is deployed
+#[derive(Debug, Deserialize)]
+pub struct Checks {
+    /// The names of the workflows that must succeed, any check
+    /// suite or workflow run is sufficient if empty
+    #[serde(default)]
+    pub workflows: Vec<String>,
+    /// How long to wait for the checks in seconds
+    #[serde(default = "default_checks_timeout")]
+    pub timeout: u64,
+}
+
+fn default_checks_timeout() -> u64 {
+    60 * 60
+}
+
+/// Filters on the files changed by a push
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Paths {
+    #[serde(default)]
+    paths: Globs,
+    #[serde(default)]
+    paths_ignore: Globs,
+}
+
+impl Paths {
+    /// Whether any filters are set
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty() && self.paths_ignore.is_empty()
+    }
+
+    /// Checks that at least one of the changed files is relevant
+    pub fn matches<I, S>(&self, files: I) -> bool
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        files.into_iter().any(|file| {
+            let file = file.as_ref();
+            let included = self.paths.is_empty() || self.paths.is_match(file);
+            included && !self.paths_ignore.is_match(file)
+        })
+    }
+}
+
+/// A set of glob patterns, where `*` does not match across directories
+#[derive(Clone, Debug, Default)]
+pub struct Globs {
+    patterns: Vec<String>,
+    set: Option<GlobSet>,
+}
+
+impl Globs {
+    /// Whether there are no patterns
+    pub fn is_empty(&self) -> bool {
+        self.set.is_none()
+    }
+
+    /// Checks if any of the patterns match, never matching if empty
+    pub fn is_match(&self, path: &str) -> bool {
+        match &self.set {
+            Some(set) => set.is_match(path),
+            None => false,
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Globs {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let patterns = Vec::<String>::deserialize(deserializer)?;
+        if patterns.is_empty() {
+            return Ok(Self::default());
+        }
+
+        let mut builder = GlobSetBuilder::new();
+        for pattern in &patterns {
+            let glob = GlobBuilder::new(pattern)
+                .literal_separator(true)
+                .build()
+                .map_err(D::Error::custom)?;
+            builder.add(glob);
+        }
+
+        let set = builder.build().map_err(D::Error::custom)?;
+        Ok(Self {
+            patterns,
+            set: Some(set),
+        })
+    }
+}
+
+impl Serialize for Globs {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.patterns.serialize(serializer)
+    }
+}
+
+/// The users allowed to trigger an event
+#[derive(Debug, Deserialize)]
+pub struct Identities {
+    pub mode: IdentityMode,
+    /// GitHub usernames
+    #[serde(default)]
+    pub logins: Vec<String>,
+    /// GitHub teams in the format <org>/<team>
+    #[serde(default)]
+    pub teams: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IdentityMode {
+    Allowlist,
+    Denylist,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Event {
     #[serde(flatten)]
     pub action: Action,
     #[serde(flatten)]
     pub mode: Mode,
+    #[serde(flatten)]
+    pub paths: Paths,
+    pub checks: Option<Checks>,
+    pub identities: Option<Identities>,
+    /// The branches and tags the event can deploy, where repository dispatches
+    /// can only deploy the default branch if empty
+    #[serde(default)]
+    pub refs: Globs,
+    /// The environment from the repository's configuration to deploy to
+    pub environment: Option<String>,
+    /// Overrides the server's debounce window
+    pub debounce: Option<u64>,
+    /// Whether to cancel the running deploy of the same checkout
+    #[serde(default)]
+    pub cancel_in_progress: bool,
+    /// The worker pool to deploy with, the default pool if omitted
+    pub pool: Option<String>,
+    /// Overrides the priority of the event's deploys in the queue
+    pub priority: Option<Priority>,
+    /// Deploy into a new release directory each time, keeping this many of them
+    pub releases: Option<u32>,
+}
+
+/// How soon a deploy runs relative to the others queued in its pool
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
 }
 
 impl Event {
     /// Checks that the repository configuration is allowed
     #[allow(clippy::ptr_arg)]
-    pub fn matches(&self, repository: &String, branch: Option<&str>) -> bool {
-        self.mode.matches(repository) && self.action.matches(branch)
+    pub fn matches(&self, repository: &String, body: &Github) -> bool {
+        self.mode.matches(repository) && self.action.matches(body) && self.allows_reference(body)
+    }
+
+    /// Checks that the branch or tag being deployed is allowed
+    fn allows_reference(&self, body: &Github) -> bool {
+        let reference = body.reference();
+        if !self.refs.is_empty() {
+            return reference.map(|r| self.refs.is_match(r)).unwrap_or_default();
+        }
+
+        // Otherwise anyone able to dispatch could deploy any branch
+        match body {
+            Github::RepositoryDispatch { branch, .. } => reference == Some(branch.as_str()),
+            _ => true,
+        }
     }
 }
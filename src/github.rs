@@ -1,4 +1,9 @@
 use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashSet;
+
+/// The maximum number of commits GitHub includes in a push
+const MAX_PUSH_COMMITS: usize = 2048;
 
 /// The overarching webhook types
 #[derive(Debug, Deserialize)]
@@ -9,15 +14,62 @@ pub enum Github {
         hook_id: i64,
     },
     Push {
+        before: String,
         after: String,
         #[serde(rename = "ref")]
         reference: String,
         repository: Repository,
+        #[serde(default)]
+        commits: Vec<Commit>,
+        head_commit: Option<Commit>,
+        pusher: Option<Pusher>,
+        sender: Option<User>,
+        installation: Option<Installation>,
     },
     Release {
         action: ReleaseAction,
         repository: Repository,
         release: Release,
+        sender: Option<User>,
+        installation: Option<Installation>,
+    },
+    PullRequest {
+        action: PullRequestAction,
+        number: u64,
+        pull_request: PullRequest,
+        repository: Repository,
+        sender: Option<User>,
+        installation: Option<Installation>,
+    },
+    CheckSuite {
+        action: CheckAction,
+        check_suite: CheckSuite,
+        repository: Repository,
+    },
+    WorkflowRun {
+        action: CheckAction,
+        workflow_run: WorkflowRun,
+        repository: Repository,
+    },
+    // Must come before deployments as it also contains the deployment
+    DeploymentStatus {
+        deployment_status: DeploymentStatus,
+        repository: Repository,
+    },
+    Deployment {
+        deployment: Deployment,
+        repository: Repository,
+        sender: Option<User>,
+        installation: Option<Installation>,
+    },
+    RepositoryDispatch {
+        #[serde(rename = "action")]
+        event_type: String,
+        branch: String,
+        client_payload: Value,
+        repository: Repository,
+        sender: Option<User>,
+        installation: Option<Installation>,
     },
 }
 
@@ -28,8 +80,111 @@ impl Github {
             Self::Ping { .. } => "ping",
             Self::Push { .. } => "push",
             Self::Release { .. } => "release",
+            Self::PullRequest { .. } => "pull_request",
+            Self::CheckSuite { .. } => "check_suite",
+            Self::WorkflowRun { .. } => "workflow_run",
+            Self::DeploymentStatus { .. } => "deployment_status",
+            Self::Deployment { .. } => "deployment",
+            Self::RepositoryDispatch { .. } => "repository_dispatch",
         }
     }
+
+    /// Get the login of the user that triggered the webhook
+    pub fn actor(&self) -> Option<&str> {
+        let sender = match self {
+            Self::Push {
+                pusher: Some(pusher),
+                ..
+            } => return Some(&pusher.name),
+            Self::Push { sender, .. } => sender,
+            Self::Release { sender, .. } => sender,
+            Self::PullRequest { sender, .. } => sender,
+            Self::Deployment { sender, .. } => sender,
+            Self::RepositoryDispatch { sender, .. } => sender,
+            _ => return None,
+        };
+
+        sender.as_ref().map(|s| s.login.as_str())
+    }
+
+    /// Get the ID of the GitHub App installation that sent the webhook
+    pub fn installation(&self) -> Option<u64> {
+        let installation = match self {
+            Self::Push { installation, .. } => installation,
+            Self::Release { installation, .. } => installation,
+            Self::PullRequest { installation, .. } => installation,
+            Self::Deployment { installation, .. } => installation,
+            Self::RepositoryDispatch { installation, .. } => installation,
+            _ => return None,
+        };
+
+        installation.as_ref().map(|i| i.id)
+    }
+
+    /// Get the branch or tag the webhook deploys without its `refs/` prefix,
+    /// where commits are deployed from the default branch
+    pub fn reference(&self) -> Option<&str> {
+        match self {
+            Self::Push { reference, .. } => Some(reference.trim_start_matches("refs/heads/")),
+            Self::Release { release, .. } => Some(&release.tag_name),
+            Self::Deployment {
+                deployment,
+                repository,
+                ..
+            } if is_commit(&deployment.reference) => repository.default_branch.as_deref(),
+            Self::Deployment { deployment, .. } => Some(
+                deployment
+                    .reference
+                    .trim_start_matches("refs/heads/")
+                    .trim_start_matches("refs/tags/"),
+            ),
+            Self::RepositoryDispatch {
+                branch,
+                client_payload,
+                ..
+            } => match client_payload.get("ref").and_then(Value::as_str) {
+                Some(r) if !r.is_empty() && !is_commit(r) => Some(r),
+                _ => Some(branch),
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Check if the reference is a full commit hash rather than a branch or tag
+pub fn is_commit(reference: &str) -> bool {
+    reference.len() == 40 && reference.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Information about a pushed commit
+#[derive(Debug, Deserialize)]
+pub struct Commit {
+    #[serde(default)]
+    pub message: String,
+    #[serde(default)]
+    pub added: Vec<String>,
+    #[serde(default)]
+    pub modified: Vec<String>,
+    #[serde(default)]
+    pub removed: Vec<String>,
+}
+
+impl Commit {
+    /// Get all the files changed by the commits. Returns `None` if the commits
+    /// might not include every change, i.e. when the list was truncated or
+    /// the branch was reset to an existing commit.
+    pub fn changed_files(commits: &[Self]) -> Option<HashSet<&str>> {
+        if commits.is_empty() || commits.len() >= MAX_PUSH_COMMITS {
+            return None;
+        }
+
+        let files = commits
+            .iter()
+            .flat_map(|c| c.added.iter().chain(&c.modified).chain(&c.removed))
+            .map(String::as_str)
+            .collect();
+        Some(files)
+    }
 }
 
 /// Possible release actions that can be done
@@ -45,10 +200,133 @@ pub enum ReleaseAction {
     Released,
 }
 
+impl ReleaseAction {
+    /// Whether the release is no longer available
+    pub fn is_removal(&self) -> bool {
+        matches!(self, Self::Unpublished | Self::Deleted)
+    }
+}
+
 /// Information about a release
 #[derive(Debug, Deserialize)]
 pub struct Release {
     pub tag_name: String,
+    pub prerelease: bool,
+    pub draft: bool,
+}
+
+/// Possible pull request actions that can be done
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PullRequestAction {
+    Opened,
+    Reopened,
+    Synchronize,
+    Closed,
+    #[serde(other)]
+    Other,
+}
+
+/// Information about a pull request
+#[derive(Debug, Deserialize)]
+pub struct PullRequest {
+    pub head: Head,
+}
+
+impl PullRequest {
+    /// Check if the pull request comes from a different repository
+    pub fn is_fork(&self, base: &Repository) -> bool {
+        match &self.head.repo {
+            Some(repo) => repo.name != base.name,
+            None => true, // The fork was deleted
+        }
+    }
+}
+
+/// The source of a pull request
+#[derive(Debug, Deserialize)]
+pub struct Head {
+    pub sha: String,
+    pub repo: Option<Repository>,
+}
+
+/// Possible check suite and workflow run actions that can be done
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckAction {
+    Completed,
+    #[serde(other)]
+    Other,
+}
+
+/// The result of a completed check suite or workflow run
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Conclusion {
+    Success,
+    Neutral,
+    Skipped,
+    #[serde(other)]
+    Other,
+}
+
+impl Conclusion {
+    /// Whether the conclusion allows a deployment to continue
+    pub fn passed(conclusion: &Option<Self>) -> bool {
+        matches!(
+            conclusion,
+            Some(Self::Success) | Some(Self::Neutral) | Some(Self::Skipped)
+        )
+    }
+}
+
+/// Information about a check suite
+#[derive(Debug, Deserialize)]
+pub struct CheckSuite {
+    pub head_sha: String,
+    pub conclusion: Option<Conclusion>,
+}
+
+/// Information about a GitHub Actions workflow run
+#[derive(Debug, Deserialize)]
+pub struct WorkflowRun {
+    pub name: String,
+    pub head_sha: String,
+    pub conclusion: Option<Conclusion>,
+}
+
+/// Information about a deployment created through the API
+#[derive(Debug, Deserialize)]
+pub struct Deployment {
+    pub id: u64,
+    pub sha: String,
+    #[serde(rename = "ref")]
+    pub reference: String,
+    pub environment: String,
+}
+
+/// Information about a deployment's status
+#[derive(Debug, Deserialize)]
+pub struct DeploymentStatus {
+    pub state: String,
+}
+
+/// The user that pushed the commits
+#[derive(Debug, Deserialize)]
+pub struct Pusher {
+    pub name: String,
+}
+
+/// A GitHub user
+#[derive(Debug, Deserialize)]
+pub struct User {
+    pub login: String,
+}
+
+/// The GitHub App installation that sent a webhook
+#[derive(Debug, Deserialize)]
+pub struct Installation {
+    pub id: u64,
 }
 
 /// The repository information
@@ -57,4 +335,6 @@ pub struct Repository {
     #[serde(rename = "full_name")]
     pub name: String,
     pub clone_url: String,
+    pub ssh_url: Option<String>,
+    pub default_branch: Option<String>,
 }
@@ -2,12 +2,16 @@ use super::{
     errors::{SignatureError, UndeployableError},
     SharedConfig,
 };
-use crate::github::Github;
+use crate::{
+    api::Client,
+    config::{Event, Identities, IdentityMode},
+    github::Github,
+};
 use ring::hmac;
-use tracing::warn;
+use tracing::{error, warn};
 use warp::{reject, Rejection};
 
-type Result = std::result::Result<(), Rejection>;
+type Result<T = ()> = std::result::Result<T, Rejection>;
 
 /// Ensure that the signature from github is valid
 pub(crate) fn valid_signature(raw_body: &[u8], raw_signature: String, secret: &[u8]) -> Result {
@@ -31,16 +35,55 @@ pub(crate) fn valid_signature(raw_body: &[u8], raw_signature: String, secret: &[
     hmac::verify(&key, raw_body, &signature).map_err(|_| reject::custom(SignatureError))
 }
 
-/// Check that the received repository is allowed to be deployed
-pub(crate) fn deployable(config: &SharedConfig, body: &Github) -> Result {
+/// Check that the received repository is allowed to be deployed,
+/// returning the event configuration that allowed it (if any)
+pub(crate) async fn deployable<'c>(
+    config: &'c SharedConfig,
+    api: &Client,
+    body: &Github,
+) -> Result<Option<&'c Event>> {
+    let event = matching_event(config, api, body).await?;
+
+    // Pull requests from forks control the deployment configuration,
+    // so they must be explicitly allowed
+    if let Github::PullRequest {
+        number,
+        pull_request,
+        repository,
+        ..
+    } = body
+    {
+        let allowed = event.map(|e| e.action.allows_forks()).unwrap_or_default();
+        if pull_request.is_fork(repository) && !allowed {
+            warn!(
+                "attempt to preview {}#{} from a fork was blocked",
+                repository.name, number
+            );
+            return Err(reject::custom(UndeployableError));
+        }
+    }
+
+    Ok(event)
+}
+
+/// Find the event configuration allowing the repository to be deployed
+async fn matching_event<'c>(
+    config: &'c SharedConfig,
+    api: &Client,
+    body: &Github,
+) -> Result<Option<&'c Event>> {
     // Default to allow
     if config.events.is_empty() {
-        return Ok(());
+        return Ok(None);
     }
 
     // Get the repository name and branch (if push)
     let (name, branch) = match body {
-        Github::Ping { .. } => return Ok(()), // Pings are always allowed
+        Github::Ping { .. } => return Ok(None), // Pings are always allowed
+        // Check results only release deployments that were already allowed
+        Github::CheckSuite { .. } | Github::WorkflowRun { .. } => return Ok(None),
+        // Statuses are only reported, never acted upon
+        Github::DeploymentStatus { .. } => return Ok(None),
         Github::Push {
             repository,
             reference,
@@ -52,16 +95,35 @@ pub(crate) fn deployable(config: &SharedConfig, body: &Github) -> Result {
             (&repository.name, Some(pushed_branch))
         }
         Github::Release { repository, .. } => (&repository.name, None),
+        Github::PullRequest { repository, .. } => (&repository.name, None),
+        Github::Deployment { repository, .. } => (&repository.name, None),
+        Github::RepositoryDispatch { repository, .. } => (&repository.name, None),
     };
 
-    // Check the branch and repository name are allowed
+    // Check the branch and repository name are allowed, then
+    // that the user is allowed to trigger it
+    let mut identity_blocked = false;
     for event in &config.events {
-        if event.matches(name, branch) {
-            return Ok(());
+        if !event.matches(name, body) {
+            continue;
+        }
+
+        match &event.identities {
+            Some(identities) if !permitted(identities, body.actor(), api).await => {
+                identity_blocked = true;
+            }
+            _ => return Ok(Some(event)),
         }
     }
 
-    if let Some(branch) = branch {
+    if identity_blocked {
+        warn!(
+            "attempt to deploy {} on {} by {} was blocked by identity",
+            name,
+            body.name(),
+            body.actor().unwrap_or("unknown user")
+        );
+    } else if let Some(branch) = branch {
         warn!(
             "attempt to deploy {}#{} on {} was blocked",
             name,
@@ -73,3 +135,48 @@ pub(crate) fn deployable(config: &SharedConfig, body: &Github) -> Result {
     }
     Err(reject::custom(UndeployableError))
 }
+
+/// Check that the user is allowed by the identity restrictions
+async fn permitted(identities: &Identities, actor: Option<&str>, api: &Client) -> bool {
+    // Unknown users are never trusted
+    let login = match actor {
+        Some(login) => login,
+        None => return false,
+    };
+
+    let listed = if identities
+        .logins
+        .iter()
+        .any(|l| l.eq_ignore_ascii_case(login))
+    {
+        true
+    } else {
+        match in_teams(&identities.teams, login, api).await {
+            Ok(listed) => listed,
+            Err(e) => {
+                error!(error = %e, "failed to check team membership for {}", login);
+                return false;
+            }
+        }
+    };
+
+    match identities.mode {
+        IdentityMode::Allowlist => listed,
+        IdentityMode::Denylist => !listed,
+    }
+}
+
+/// Check if the user is a member of any of the teams
+async fn in_teams(teams: &[String], login: &str, api: &Client) -> anyhow::Result<bool> {
+    for team in teams {
+        let (org, slug) = team
+            .split_once('/')
+            .ok_or_else(|| anyhow::anyhow!("invalid team {}, must be <org>/<team>", team))?;
+
+        if api.is_team_member(org, slug, login).await? {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
@@ -1,26 +1,35 @@
 use super::{
     access,
-    errors::{BodyParsingError, GitError},
-    SharedConfig,
+    errors::{BodyParsingError, SignatureError},
+    SharedConfig, SharedPending,
 };
 use crate::{
-    github::{Github, ReleaseAction},
-    processor::Message,
-    repo,
+    api::Client,
+    config::{self, Priority, Target},
+    github::{
+        is_commit, CheckAction, Commit, Conclusion, Github, PullRequestAction, ReleaseAction,
+    },
+    processor::{self, Changes, Kind, Message, Queue, Update, Variables},
 };
-use async_channel::Sender;
 use bytes::Bytes;
-use git2::Repository;
-use std::path::Path;
-use tracing::info;
-use warp::{http::StatusCode, reject, Rejection, Reply};
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::json;
+use std::path::PathBuf;
+use tracing::{debug, info};
+use warp::{http::StatusCode, reject, reply, Rejection, Reply};
+
+/// How many seconds a signed rollback request is accepted for
+const ROLLBACK_MAX_AGE: i64 = 300;
 
 /// Handle receiving webhooks from GitHub
 pub async fn hook(
     raw_body: Bytes,
     raw_signature: String,
     config: SharedConfig,
-    sender: Sender<Message>,
+    api: Client,
+    pending: SharedPending,
+    queue: Queue,
 ) -> Result<impl Reply, Rejection> {
     // Ensure the signature is valid
     access::valid_signature(&raw_body, raw_signature, config.server.secret.as_bytes())?;
@@ -31,92 +40,387 @@ pub async fn hook(
     info!("got new {} hook", body.name());
 
     // Ensure the repository is allowed to be deployed
-    access::deployable(&config, &body)?;
+    let event = access::deployable(&config, &api, &body).await?;
+    let environment = event.and_then(|e| e.environment.clone());
+
+    // How the event's deploys are queued
+    let debounce = event
+        .and_then(|e| e.debounce)
+        .unwrap_or(config.server.debounce);
+    let cancel_in_progress = event.map(|e| e.cancel_in_progress).unwrap_or_default();
+    let pool = event.and_then(|e| e.pool.clone());
+    let priority = event
+        .and_then(|e| e.priority)
+        .unwrap_or_else(|| default_priority(&body));
+    let releases = event.and_then(|e| e.releases);
+    let installation = body.installation();
+    let queued = |message: Message| {
+        message
+            .installation(installation)
+            .debounce(debounce)
+            .cancel_in_progress(cancel_in_progress)
+            .pool(pool.clone())
+            .priority(priority)
+            .releases(releases)
+    };
 
     // Extract the repository information and reference
-    let (repository, fetch_refspec, merge_refspec) = match body {
+    let message = match body {
         Github::Ping { zen, hook_id } => {
             info!("received ping from hook {}: {}", hook_id, zen);
             return Ok(StatusCode::NO_CONTENT);
         }
         Github::Push {
+            before,
             after,
             reference,
             repository,
-        } => (repository, reference, Some(after)),
+            commits,
+            head_commit,
+            ..
+        } => {
+            // Skip commits marked to not be deployed, deferring to the
+            // local copy when the payload doesn't include the head commit
+            let markers = config.server.skip_markers(&repository.name);
+            if let Some(commit) = &head_commit {
+                if config::skipped(&commit.message, markers) {
+                    info!("head commit of {} marked to skip deploy", repository.name);
+                    return Ok(StatusCode::NO_CONTENT);
+                }
+            }
+            let skip_markers = match head_commit {
+                Some(_) => Vec::new(),
+                None => markers.to_vec(),
+            };
+
+            // Skip pushes that don't change any relevant files, deferring to
+            // the local copy when the payload doesn't include every change
+            let paths = match event {
+                Some(event) if !event.paths.is_empty() => match Commit::changed_files(&commits) {
+                    Some(files) if !event.paths.matches(&files) => {
+                        info!("push to {} changed no deployed paths", repository.name);
+                        return Ok(StatusCode::NO_CONTENT);
+                    }
+                    Some(_) => None,
+                    None => Some(event.paths.clone()),
+                },
+                _ => None,
+            };
+
+            let target = match &environment {
+                Some(environment) => Target::Environment(environment.clone()),
+                None => Target::reference(&reference),
+            };
+            let path = checkout_path(&config, &repository.name, target)?;
+            let message = Message::new(path, repository.name, Kind::Deploy)
+                .update(Update::Fetch {
+                    clone_url: repository.clone_url,
+                    ssh_url: repository.ssh_url,
+                    refspec: reference,
+                    commit: Some(after.clone()),
+                    changes: Some(Box::new(Changes {
+                        before,
+                        paths,
+                        skip_markers,
+                    })),
+                })
+                .environment(environment);
+
+            // Wait for the checks to pass if necessary
+            if let Some(checks) = event.and_then(|e| e.checks.as_ref()) {
+                pending.hold(after, checks, queued(message));
+                return Ok(StatusCode::ACCEPTED);
+            }
+
+            message
+        }
+        Github::Release {
+            action,
+            repository,
+            release,
+            ..
+        } if action.is_removal() => {
+            // Only revert when configured to
+            if !event.map(|e| e.action.reverts()).unwrap_or_default() {
+                return Ok(StatusCode::NO_CONTENT);
+            }
+
+            let target = release_target(&environment);
+            let path = checkout_path(&config, &repository.name, target)?;
+            Message::new(path, repository.name, Kind::Deploy)
+                .update(Update::Revert {
+                    tag: release.tag_name,
+                })
+                .environment(environment)
+        }
         Github::Release {
             action,
             repository,
             release,
+            ..
         } => {
-            // Only do stuff when released
-            if action == ReleaseAction::Released {
-                let tag_refspec = format!("refs/tags/{}", release.tag_name);
-                (repository, tag_refspec, None)
+            // Only do stuff when released, unless configured otherwise
+            if event.is_some() || action == ReleaseAction::Released {
+                let target = release_target(&environment);
+                let path = checkout_path(&config, &repository.name, target)?;
+                Message::new(path, repository.name, Kind::Deploy)
+                    .update(Update::Fetch {
+                        clone_url: repository.clone_url,
+                        ssh_url: repository.ssh_url,
+                        refspec: format!("refs/tags/{}", release.tag_name),
+                        commit: None,
+                        changes: None,
+                    })
+                    .environment(environment)
             } else {
                 return Ok(StatusCode::NO_CONTENT);
             }
         }
+        Github::PullRequest {
+            action,
+            number,
+            pull_request,
+            repository,
+            ..
+        } => {
+            let path = checkout_path(&config, &repository.name, Target::PullRequest(number))?;
+
+            match action {
+                PullRequestAction::Opened
+                | PullRequestAction::Reopened
+                | PullRequestAction::Synchronize => {
+                    // Pull from the base repository so forks don't need their own remote
+                    Message::new(path, repository.name, Kind::Preview).update(Update::Fetch {
+                        clone_url: repository.clone_url,
+                        ssh_url: repository.ssh_url,
+                        refspec: format!("refs/pull/{}/head", number),
+                        commit: Some(pull_request.head.sha),
+                        changes: None,
+                    })
+                }
+                PullRequestAction::Closed => {
+                    // Nothing to tear down if it was never previewed
+                    if path.exists() {
+                        queued(Message::new(path, repository.name, Kind::Teardown))
+                            .send(&queue)
+                            .await;
+                    }
+                    return Ok(StatusCode::NO_CONTENT);
+                }
+                PullRequestAction::Other => return Ok(StatusCode::NO_CONTENT),
+            }
+        }
+        Github::CheckSuite {
+            action,
+            check_suite,
+            repository,
+        } => {
+            if action == CheckAction::Completed {
+                let passed = Conclusion::passed(&check_suite.conclusion);
+                let ready = pending.complete(&repository.name, &check_suite.head_sha, None, passed);
+                for message in ready {
+                    message.send(&queue).await;
+                }
+            }
+
+            return Ok(StatusCode::NO_CONTENT);
+        }
+        Github::WorkflowRun {
+            action,
+            workflow_run,
+            repository,
+        } => {
+            if action == CheckAction::Completed {
+                let passed = Conclusion::passed(&workflow_run.conclusion);
+                let ready = pending.complete(
+                    &repository.name,
+                    &workflow_run.head_sha,
+                    Some(&workflow_run.name),
+                    passed,
+                );
+                for message in ready {
+                    message.send(&queue).await;
+                }
+            }
+
+            return Ok(StatusCode::NO_CONTENT);
+        }
+        Github::DeploymentStatus {
+            deployment_status,
+            repository,
+        } => {
+            debug!(
+                "ignoring {} deployment status for {}",
+                deployment_status.state, repository.name
+            );
+            return Ok(StatusCode::NO_CONTENT);
+        }
+        Github::Deployment {
+            deployment,
+            repository,
+            ..
+        } => {
+            let target = Target::Environment(deployment.environment.clone());
+            let path = checkout_path(&config, &repository.name, target)?;
+
+            // Deployments of a commit are looked up from the default branch
+            let refspec = if is_commit(&deployment.reference) {
+                "HEAD".to_string()
+            } else {
+                deployment.reference
+            };
+
+            Message::new(path, repository.name, Kind::Deploy)
+                .update(Update::Fetch {
+                    clone_url: repository.clone_url,
+                    ssh_url: repository.ssh_url,
+                    refspec,
+                    commit: Some(deployment.sha),
+                    changes: None,
+                })
+                .environment(Some(deployment.environment))
+                .deployment(Some(deployment.id))
+        }
+        Github::RepositoryDispatch {
+            branch,
+            client_payload,
+            repository,
+            ..
+        } => {
+            let variables = Variables::from_json(&client_payload);
+
+            // The payload can choose the branch, tag or commit to deploy,
+            // where commits are looked up from the default branch
+            let (reference, head) = match variables.get("ref") {
+                Some(r) if is_commit(r) => (branch, Some(r.to_string())),
+                Some(r) if !r.is_empty() => (r.to_string(), None),
+                _ => (branch, None),
+            };
+
+            // Deploys without an environment use the checkout of the deployed branch or tag
+            let target = match &environment {
+                Some(environment) => Target::Environment(environment.clone()),
+                None => Target::reference(&reference),
+            };
+            let path = checkout_path(&config, &repository.name, target)?;
+
+            Message::new(path, repository.name, Kind::Deploy)
+                .update(Update::Fetch {
+                    clone_url: repository.clone_url,
+                    ssh_url: repository.ssh_url,
+                    refspec: reference,
+                    commit: head,
+                    changes: None,
+                })
+                .environment(environment)
+                .variables(variables)
+        }
     };
 
-    // Build the repository path
-    let folder_name = repository.name.replace("/", "__");
-    let path = config.server.repositories.join(folder_name);
-
-    // Update the repository in a separate thread
-    let arg_path = path.clone();
-    let arg_repo = repository.clone();
-    tokio::task::spawn_blocking(move || {
-        update_repo(
-            &arg_path,
-            &arg_repo.name,
-            &arg_repo.clone_url,
-            fetch_refspec,
-            merge_refspec,
-        )
-    })
-    .await
-    .unwrap()
-    .map_err(|e| reject::custom(GitError(e)))?;
-
-    // Queue the repository for processing
-    Message::send(sender, path, repository.name).await;
+    queued(message).send(&queue).await;
 
     Ok(StatusCode::NO_CONTENT)
 }
 
-/// Update the local copy of the repository
-fn update_repo(
-    path: &Path,
-    name: &str,
-    clone_url: &str,
-    fetch_refspec: String,
-    merge_refspec: Option<String>,
-) -> Result<(), git2::Error> {
-    // Initialize the repository
-    let repo = Repository::init(path)?;
-
-    // Get the repository's remote to pull
-    repo.remote_set_url("origin", clone_url)?;
-    let mut remote = repo.find_remote("origin").unwrap();
-
-    // Download the repository
-    // TODO: support private repositories
-    info!("pulling {} for {}", fetch_refspec, name);
-    let fetch_commit = repo::fetch(&repo, &[&fetch_refspec], &mut remote)?;
-
-    // Merge the fetched data
-    info!(
-        "merging into {}",
-        fetch_commit.refname().unwrap_or(&fetch_refspec)
-    );
-    repo::merge(&repo, &fetch_refspec, fetch_commit)?;
-
-    // Checkout the last pushed commit (if it is a push)
-    if let Some(commit) = merge_refspec {
-        info!("checking out commit {}", commit);
-        repo::checkout(&repo, &commit)?;
+/// Manual deploys run ahead of pushes and releases, which run ahead of previews
+fn default_priority(body: &Github) -> Priority {
+    match body {
+        Github::Deployment { .. } | Github::RepositoryDispatch { .. } => Priority::High,
+        Github::PullRequest { .. } => Priority::Low,
+        _ => Priority::Normal,
+    }
+}
+
+/// Get the state of the worker pools
+pub async fn status(queue: Queue) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&json!({ "pools": queue.status() })))
+}
+
+/// The body of a rollback request
+#[derive(Deserialize)]
+pub struct Rollback {
+    /// The repository the request was signed for
+    pub repository: String,
+    /// When the request was signed, as a Unix timestamp
+    pub timestamp: i64,
+    /// The checkout to roll back, as it is named in the checkout's folder
+    pub target: String,
+    /// The commit or GitHub deployment to roll back to, the previous deploy if omitted
+    pub to: Option<String>,
+}
+
+/// Roll the checkout back to an earlier successful deploy
+pub async fn rollback(
+    owner: String,
+    name: String,
+    raw_body: Bytes,
+    raw_signature: String,
+    config: SharedConfig,
+    queue: Queue,
+) -> Result<impl Reply, Rejection> {
+    // Requests are signed the same way as webhooks
+    access::valid_signature(&raw_body, raw_signature, config.server.secret.as_bytes())?;
+    let body: Rollback =
+        serde_json::from_slice(&raw_body).map_err(|_| reject::custom(BodyParsingError))?;
+
+    // The signature only covers the repository and time in the body
+    let repository = format!("{}/{}", owner, name);
+    if !body.repository.eq_ignore_ascii_case(&repository) {
+        return Err(reject::custom(SignatureError));
+    }
+    if (Utc::now().timestamp() - body.timestamp).abs() > ROLLBACK_MAX_AGE {
+        debug!("rejecting rollback signed at {}", body.timestamp);
+        return Err(reject::custom(SignatureError));
     }
 
-    Ok(())
+    // Only checkouts with an earlier deploy can be rolled back, which is
+    // deployed the same way it originally was
+    let target: Target = body
+        .target
+        .parse()
+        .map_err(|_| reject::custom(BodyParsingError))?;
+    let path = checkout_path(&config, &repository, target)?;
+    let deployed = processor::find_deployed(&path, body.to.clone())
+        .await
+        .ok_or_else(reject::not_found)?;
+    info!("rolling back {} for {}", repository, body.target);
+
+    let message = Message::new(path, repository, Kind::Deploy)
+        .update(Update::Rollback { to: body.to })
+        .priority(Priority::High)
+        .releases(deployed.releases)
+        .pool(deployed.pool)
+        .installation(deployed.installation)
+        .variables(deployed.variables);
+    let id = message.id;
+    message.send(&queue).await;
+
+    Ok(reply::with_status(
+        reply::json(&json!({ "id": id })),
+        StatusCode::ACCEPTED,
+    ))
+}
+
+/// Get the checkout target for a release, which is shared by every release
+/// without an environment
+fn release_target(environment: &Option<String>) -> Target {
+    match environment {
+        Some(environment) => Target::Environment(environment.clone()),
+        None => Target::Releases,
+    }
+}
+
+/// Get the path to the checkout of a target, rejecting targets that can't be used
+fn checkout_path(
+    config: &SharedConfig,
+    repository: &str,
+    target: Target,
+) -> Result<PathBuf, Rejection> {
+    config
+        .server
+        .checkout_path(repository, &target)
+        .map_err(|e| {
+            info!("rejecting checkout of {}: {}", repository, e);
+            reject::custom(BodyParsingError)
+        })
 }
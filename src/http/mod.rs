@@ -1,5 +1,4 @@
-use crate::{config::Config, processor::Message};
-use async_channel::Sender;
+use crate::{api::Client, config::Config, processor::Queue};
 use std::{convert::Infallible, sync::Arc};
 use tracing::info;
 use warp::{http::StatusCode, Filter, Rejection, Reply};
@@ -7,29 +6,41 @@ use warp::{http::StatusCode, Filter, Rejection, Reply};
 mod access;
 mod errors;
 mod handlers;
+mod pending;
 
 pub use errors::recover;
+use pending::Pending;
 
 type SharedConfig = Arc<Config>;
+type SharedPending = Arc<Pending>;
 
 fn with_config(
-    config: Config,
+    config: SharedConfig,
 ) -> impl Filter<Extract = (SharedConfig,), Error = Infallible> + Clone {
-    let config = Arc::new(config);
     warp::any().map(move || config.clone())
 }
 
-fn with_sender(
-    sender: Sender<Message>,
-) -> impl Filter<Extract = (Sender<Message>,), Error = Infallible> + Clone {
-    warp::any().map(move || sender.clone())
+fn with_api(api: Client) -> impl Filter<Extract = (Client,), Error = Infallible> + Clone {
+    warp::any().map(move || api.clone())
+}
+
+fn with_pending() -> impl Filter<Extract = (SharedPending,), Error = Infallible> + Clone {
+    let pending = Arc::new(Pending::default());
+    warp::any().map(move || pending.clone())
+}
+
+fn with_queue(queue: Queue) -> impl Filter<Extract = (Queue,), Error = Infallible> + Clone {
+    warp::any().map(move || queue.clone())
 }
 
 /// Build the routes for the API
 pub fn routes(
     config: Config,
-    sender: Sender<Message>,
+    api: Client,
+    queue: Queue,
 ) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let config = Arc::new(config);
+
     // Health check route
     let health = warp::path("health")
         .and(warp::get())
@@ -39,6 +50,24 @@ pub fn routes(
         })
         .with(warp::trace::named("health"));
 
+    // Queue status route
+    let status = warp::path("status")
+        .and(warp::get())
+        .and(with_queue(queue.clone()))
+        .and_then(handlers::status)
+        .with(warp::trace::named("status"));
+
+    // Manual rollback route
+    let rollback = warp::path!("repos" / String / String / "rollback")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::bytes())
+        .and(warp::header::<String>("X-Hub-Signature-256"))
+        .and(with_config(config.clone()))
+        .and(with_queue(queue.clone()))
+        .and_then(handlers::rollback)
+        .with(warp::trace::named("rollback"));
+
     // Main hook route
     let hook = warp::path::end()
         .and(warp::post())
@@ -46,9 +75,11 @@ pub fn routes(
         .and(warp::body::bytes())
         .and(warp::header::<String>("X-Hub-Signature-256"))
         .and(with_config(config))
-        .and(with_sender(sender))
+        .and(with_api(api))
+        .and(with_pending())
+        .and(with_queue(queue))
         .and_then(handlers::hook)
         .with(warp::trace::named("hook"));
 
-    health.or(hook)
+    health.or(status).or(rollback).or(hook)
 }
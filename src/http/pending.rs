@@ -0,0 +1,120 @@
+use crate::{config::Checks, processor::Message};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// Deployments waiting for their checks to complete
+#[derive(Default)]
+pub struct Pending {
+    held: Mutex<HashMap<Uuid, Held>>,
+}
+
+/// A deployment held until its checks complete
+struct Held {
+    sha: String,
+    /// The workflows that have yet to succeed, or `None` if any check is sufficient
+    remaining: Option<HashSet<String>>,
+    message: Message,
+}
+
+impl Pending {
+    /// Hold a deployment until the checks for its commit pass,
+    /// dropping it if they do not complete within the timeout
+    pub fn hold(self: &Arc<Self>, sha: String, checks: &Checks, message: Message) {
+        let id = Uuid::new_v4();
+        let remaining = if checks.workflows.is_empty() {
+            None
+        } else {
+            Some(checks.workflows.iter().cloned().collect())
+        };
+
+        info!(
+            "holding deploy of {} at {} until checks complete",
+            message.repository, sha
+        );
+        self.held.lock().unwrap().insert(
+            id,
+            Held {
+                sha,
+                remaining,
+                message,
+            },
+        );
+
+        // Expire the deployment after the timeout
+        let pending = self.clone();
+        let timeout = Duration::from_secs(checks.timeout);
+        tokio::spawn(async move {
+            tokio::time::sleep(timeout).await;
+            if let Some(held) = pending.held.lock().unwrap().remove(&id) {
+                warn!(
+                    "checks for {} at {} did not complete in time, dropping deploy",
+                    held.message.repository, held.sha
+                );
+            }
+        });
+    }
+
+    /// Record the result of a check suite or workflow run, returning the
+    /// deployments that are now ready to be run
+    pub fn complete(
+        &self,
+        repository: &str,
+        sha: &str,
+        workflow: Option<&str>,
+        passed: bool,
+    ) -> Vec<Message> {
+        let mut held = self.held.lock().unwrap();
+
+        let mut ready = Vec::new();
+        let mut finished = Vec::new();
+        for (id, entry) in held.iter_mut() {
+            if entry.sha != sha || entry.message.repository != repository {
+                continue;
+            }
+
+            // Check if the result is one that was waited on
+            let required = match (&mut entry.remaining, workflow) {
+                (None, _) => true,
+                (Some(remaining), Some(name)) => remaining.contains(name),
+                (Some(_), None) => false,
+            };
+            if !required {
+                continue;
+            }
+
+            if !passed {
+                warn!(
+                    "checks for {} at {} failed, dropping deploy",
+                    repository, sha
+                );
+                finished.push(*id);
+            } else if let (Some(remaining), Some(name)) = (&mut entry.remaining, workflow) {
+                remaining.remove(name);
+                if remaining.is_empty() {
+                    finished.push(*id);
+                    ready.push(*id);
+                }
+            } else {
+                finished.push(*id);
+                ready.push(*id);
+            }
+        }
+
+        // Remove the completed deployments
+        let mut messages = Vec::new();
+        for id in finished {
+            let entry = held.remove(&id).unwrap();
+            if ready.contains(&id) {
+                info!("checks for {} at {} passed", repository, sha);
+                messages.push(entry.message);
+            }
+        }
+
+        messages
+    }
+}
@@ -0,0 +1,704 @@
+use anyhow::{anyhow, bail, Context, Result};
+use git2::{ObjectType, Repository, TreeWalkMode, TreeWalkResult};
+use reqwest::{
+    blocking::Client,
+    header::{HeaderMap, HeaderName, HeaderValue, ACCEPT, CONTENT_TYPE},
+    Url,
+};
+use ring::digest::{Context as Digest, SHA256};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+use tracing::{debug, error, info, warn};
+
+/// The first line of every pointer file
+const VERSION: &str = "version https://git-lfs.github.com/spec/v1";
+
+/// Pointer files are always smaller than this
+const MAX_POINTER_SIZE: usize = 1024;
+
+/// The most objects GitHub accepts in a single batch request
+const MAX_BATCH_SIZE: usize = 100;
+
+/// The content type of batch API requests and responses
+const MEDIA_TYPE: &str = "application/vnd.git-lfs+json";
+
+/// A reference to an object stored in LFS, checked in instead of the file
+#[derive(Clone, Debug, Eq, Hash, PartialEq, Serialize)]
+pub struct Pointer {
+    pub oid: String,
+    pub size: u64,
+}
+
+impl Pointer {
+    /// Parse the contents of a file, returning `None` if it is not a pointer
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() >= MAX_POINTER_SIZE {
+            return None;
+        }
+
+        let text = std::str::from_utf8(data).ok()?;
+        let mut lines = text.lines();
+        if lines.next()? != VERSION {
+            return None;
+        }
+
+        let (mut oid, mut size) = (None, None);
+        for line in lines {
+            match line.split_once(' ') {
+                Some(("oid", value)) => oid = value.strip_prefix("sha256:"),
+                Some(("size", value)) => size = value.parse().ok(),
+                _ => {}
+            }
+        }
+
+        let oid = oid.filter(|o| o.len() == 64 && o.chars().all(|c| c.is_ascii_hexdigit()))?;
+        Some(Self {
+            oid: oid.to_ascii_lowercase(),
+            size: size?,
+        })
+    }
+
+    /// Check if the file has the contents the pointer refers to
+    pub fn matches(&self, path: &Path) -> bool {
+        match fs::metadata(path) {
+            Ok(m) if m.len() == self.size => {}
+            _ => return false,
+        }
+
+        match File::open(path).and_then(hash) {
+            Ok(oid) => oid == self.oid,
+            Err(_) => false,
+        }
+    }
+}
+
+/// Replace the pointers in the checkout with the objects they refer to,
+/// downloading any that are not already cached in `.git/lfs/objects`.
+/// Returns the number of files that were replaced.
+pub fn checkout(repo: &Repository, remote: &str, token: Option<&str>) -> Result<usize> {
+    let workdir = repo
+        .workdir()
+        .ok_or_else(|| anyhow!("repository has no working directory"))?;
+    let cache = repo.path().join("lfs").join("objects");
+
+    let pointers = find_pointers(repo)?;
+    if pointers.is_empty() {
+        return Ok(0);
+    }
+
+    // Only download the objects that are missing from the cache, where
+    // objects are verified before being added
+    let missing = pointers
+        .values()
+        .filter(|p| match fs::metadata(object_path(&cache, p)) {
+            Ok(m) => m.len() != p.size,
+            Err(_) => true,
+        })
+        .cloned()
+        .collect::<HashSet<_>>();
+    if !missing.is_empty() {
+        let endpoint = endpoint(workdir, remote)?;
+        info!(count = missing.len(), endpoint = %endpoint, "downloading LFS objects");
+
+        // The checked out `.lfsconfig` can point anywhere, so the repository's
+        // credentials only go to its own server
+        let trusted = remote_endpoint(remote).is_ok_and(|r| same_server(&endpoint, &r));
+        let token = match (token, trusted) {
+            (Some(_), false) => {
+                warn!(
+                    endpoint = %endpoint,
+                    "LFS server is not on the repository's host, downloading without credentials"
+                );
+                None
+            }
+            (token, _) => token,
+        };
+        download(&endpoint, token, &cache, missing.into_iter().collect())?;
+    }
+
+    let mut replaced = 0;
+    for (path, pointer) in &pointers {
+        let destination = workdir.join(path);
+        if pointer.matches(&destination) {
+            continue;
+        }
+
+        // Overwrite the pointer in place to keep its permissions
+        let mut source = File::open(object_path(&cache, pointer))?;
+        let mut file = File::create(&destination)
+            .with_context(|| format!("failed to write {}", destination.display()))?;
+        io::copy(&mut source, &mut file)?;
+        replaced += 1;
+    }
+
+    Ok(replaced)
+}
+
+/// Find the files in the checked out commit that are pointers
+fn find_pointers(repo: &Repository) -> Result<HashMap<PathBuf, Pointer>> {
+    let tree = repo.head()?.peel_to_tree()?;
+    let odb = repo.odb()?;
+
+    let mut pointers = HashMap::new();
+    let mut failure = None;
+    tree.walk(TreeWalkMode::PreOrder, |root, entry| {
+        if entry.kind() != Some(ObjectType::Blob) {
+            return TreeWalkResult::Ok;
+        }
+
+        // Avoid reading large files as they can't be pointers
+        let result = odb.read_header(entry.id()).and_then(|(size, _)| {
+            if size >= MAX_POINTER_SIZE {
+                return Ok(None);
+            }
+            Ok(Pointer::parse(repo.find_blob(entry.id())?.content()))
+        });
+        match result {
+            Ok(Some(pointer)) => {
+                let path =
+                    Path::new(root).join(String::from_utf8_lossy(entry.name_bytes()).as_ref());
+                pointers.insert(path, pointer);
+                TreeWalkResult::Ok
+            }
+            Ok(None) => TreeWalkResult::Ok,
+            Err(e) => {
+                failure = Some(e);
+                TreeWalkResult::Abort
+            }
+        }
+    })?;
+
+    match failure {
+        Some(e) => Err(e.into()),
+        None => Ok(pointers),
+    }
+}
+
+/// Find the LFS server for the remote, preferring the one configured
+/// in the repository's `.lfsconfig`
+fn endpoint(workdir: &Path, remote: &str) -> Result<String> {
+    let lfsconfig = workdir.join(".lfsconfig");
+    if lfsconfig.exists() {
+        if let Ok(url) = git2::Config::open(&lfsconfig)?.get_string("lfs.url") {
+            return Ok(url.trim_end_matches('/').to_string());
+        }
+    }
+
+    remote_endpoint(remote)
+}
+
+/// Find the LFS server a remote provides by default
+fn remote_endpoint(remote: &str) -> Result<String> {
+    // SSH remotes are served over HTTPS from the same host
+    let url = if let Some(rest) = remote.strip_prefix("ssh://") {
+        let rest = rest.split_once('@').map(|(_, r)| r).unwrap_or(rest);
+        let (host, path) = rest.split_once('/').unwrap_or((rest, ""));
+        let host = host.split(':').next().unwrap_or(host);
+        format!("https://{}/{}", host, path)
+    } else if let Some(rest) = remote.strip_prefix("git://") {
+        format!("https://{}", rest)
+    } else if remote.starts_with("https://") || remote.starts_with("http://") {
+        remote.to_string()
+    } else if let Some((user_host, path)) = remote.split_once(':') {
+        let host = user_host
+            .split_once('@')
+            .map(|(_, h)| h)
+            .unwrap_or(user_host);
+        format!("https://{}/{}", host, path)
+    } else {
+        bail!("cannot determine the LFS server for {}", remote);
+    };
+
+    let url = url.trim_end_matches('/');
+    match url.ends_with(".git") {
+        true => Ok(format!("{}/info/lfs", url)),
+        false => Ok(format!("{}.git/info/lfs", url)),
+    }
+}
+
+/// Check if both URLs have the same scheme, host and port
+fn same_server(a: &str, b: &str) -> bool {
+    match (Url::parse(a), Url::parse(b)) {
+        (Ok(a), Ok(b)) => {
+            a.scheme() == b.scheme()
+                && a.host_str().map(str::to_ascii_lowercase)
+                    == b.host_str().map(str::to_ascii_lowercase)
+                && a.port_or_known_default() == b.port_or_known_default()
+        }
+        _ => false,
+    }
+}
+
+/// Download the objects from the LFS server into the cache
+fn download(
+    endpoint: &str,
+    token: Option<&str>,
+    cache: &Path,
+    objects: Vec<Pointer>,
+) -> Result<()> {
+    #[derive(Serialize)]
+    struct Request<'a> {
+        operation: &'static str,
+        transfers: [&'static str; 1],
+        objects: &'a [Pointer],
+    }
+
+    #[derive(Deserialize)]
+    struct Response {
+        objects: Vec<Object>,
+    }
+
+    #[derive(Deserialize)]
+    struct Object {
+        oid: String,
+        actions: Option<Actions>,
+        error: Option<ObjectError>,
+    }
+
+    #[derive(Deserialize)]
+    struct Actions {
+        download: Action,
+    }
+
+    #[derive(Deserialize)]
+    struct Action {
+        href: String,
+        #[serde(default)]
+        header: HashMap<String, String>,
+    }
+
+    #[derive(Deserialize)]
+    struct ObjectError {
+        code: u16,
+        message: String,
+    }
+
+    let client = Client::builder()
+        .user_agent(concat!("autodeploy/", env!("CARGO_PKG_VERSION")))
+        .build()?;
+
+    let mut failed = Vec::new();
+    for batch in objects.chunks(MAX_BATCH_SIZE) {
+        let mut request = client
+            .post(format!("{}/objects/batch", endpoint))
+            .header(ACCEPT, MEDIA_TYPE)
+            .header(CONTENT_TYPE, MEDIA_TYPE)
+            .json(&Request {
+                operation: "download",
+                transfers: ["basic"],
+                objects: batch,
+            });
+        if let Some(token) = token {
+            request = request.basic_auth("x-access-token", Some(token));
+        }
+        let response: Response = request
+            .send()?
+            .error_for_status()
+            .context("LFS batch request failed")?
+            .json()?;
+
+        // Only the objects that were asked for are downloaded, using their
+        // pointers rather than the oid and size from the response
+        let mut requested = batch
+            .iter()
+            .map(|p| (p.oid.as_str(), p))
+            .collect::<HashMap<_, _>>();
+        for object in response.objects {
+            let pointer = match requested.remove(object.oid.to_ascii_lowercase().as_str()) {
+                Some(pointer) => pointer,
+                None => {
+                    warn!("ignoring LFS object that was not requested");
+                    continue;
+                }
+            };
+            let result = match (object.actions, object.error) {
+                (_, Some(e)) => Err(anyhow!("{} ({})", e.message, e.code)),
+                (Some(actions), None) => fetch_object(
+                    &client,
+                    &actions.download.href,
+                    &actions.download.header,
+                    cache,
+                    pointer,
+                ),
+                (None, None) => Err(anyhow!("no download available")),
+            };
+
+            if let Err(e) = result {
+                error!(oid = %pointer.oid, "failed to download LFS object: {:#}", e);
+                failed.push(pointer.oid.as_str());
+            }
+        }
+
+        for oid in requested.into_keys() {
+            error!(oid = %oid, "LFS server did not offer object");
+            failed.push(oid);
+        }
+    }
+
+    if !failed.is_empty() {
+        bail!("failed to download {} LFS objects", failed.len());
+    }
+    Ok(())
+}
+
+/// Download a single object, verifying it before adding it to the cache
+fn fetch_object(
+    client: &Client,
+    href: &str,
+    header: &HashMap<String, String>,
+    cache: &Path,
+    pointer: &Pointer,
+) -> Result<()> {
+    let mut headers = HeaderMap::new();
+    for (name, value) in header {
+        let mut value = HeaderValue::from_str(value)?;
+        value.set_sensitive(true);
+        headers.insert(HeaderName::from_bytes(name.as_bytes())?, value);
+    }
+
+    let mut response = client
+        .get(href)
+        .headers(headers)
+        .send()?
+        .error_for_status()?;
+
+    // Write to a temporary file so a partial download is never used
+    let path = object_path(cache, pointer);
+    fs::create_dir_all(path.parent().unwrap())?;
+    let temporary = path.with_extension("tmp");
+    let mut file = File::create(&temporary)?;
+    let size = io::copy(&mut response, &mut file)?;
+    file.flush()?;
+    drop(file);
+
+    let oid = hash(File::open(&temporary)?)?;
+    if size != pointer.size || oid != pointer.oid {
+        fs::remove_file(&temporary)?;
+        bail!(
+            "downloaded object does not match, got {} bytes with hash {}",
+            size,
+            oid
+        );
+    }
+
+    fs::rename(&temporary, &path)?;
+    debug!(oid = %pointer.oid, size, "downloaded LFS object");
+    Ok(())
+}
+
+/// Where the object is stored in the cache, using the same layout as `git lfs`
+fn object_path(cache: &Path, pointer: &Pointer) -> PathBuf {
+    cache
+        .join(&pointer.oid[0..2])
+        .join(&pointer.oid[2..4])
+        .join(&pointer.oid)
+}
+
+/// Compute the hex encoded SHA256 hash of the contents
+fn hash<R: Read>(mut reader: R) -> io::Result<String> {
+    let mut digest = Digest::new(&SHA256);
+    let mut buffer = [0; 8192];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        digest.update(&buffer[..read]);
+    }
+
+    Ok(hex::encode(digest.finish()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use git2::Signature;
+    use std::{
+        io::{BufRead, BufReader},
+        net::TcpListener,
+        sync::{Arc, Mutex},
+        thread,
+    };
+    use uuid::Uuid;
+
+    const CONTENT: &[u8] = b"the real contents\n";
+
+    fn pointer_text(oid: &str, size: u64) -> String {
+        format!("{}\noid sha256:{}\nsize {}\n", VERSION, oid, size)
+    }
+
+    fn temporary_dir() -> PathBuf {
+        let path = std::env::temp_dir().join(format!("autodeploy-lfs-{}", Uuid::new_v4()));
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    #[test]
+    fn parses_pointer() {
+        let oid = "A".repeat(64);
+        let pointer = Pointer::parse(pointer_text(&oid, 12).as_bytes()).unwrap();
+        assert_eq!(pointer.oid, "a".repeat(64));
+        assert_eq!(pointer.size, 12);
+    }
+
+    #[test]
+    fn rejects_invalid_pointers() {
+        let oid = "a".repeat(64);
+        let cases = [
+            pointer_text(&oid, 12).replace("spec/v1", "spec/v2"),
+            pointer_text(&"a".repeat(63), 12),
+            pointer_text(&"g".repeat(64), 12),
+            format!("{}\noid sha256:{}\n", VERSION, oid),
+            format!("{}\noid md5:{}\nsize 12\n", VERSION, oid),
+            format!("{}{}", pointer_text(&oid, 12), "x".repeat(MAX_POINTER_SIZE)),
+            "not a pointer".to_string(),
+        ];
+        for case in &cases {
+            assert_eq!(Pointer::parse(case.as_bytes()), None, "{:?}", case);
+        }
+        assert_eq!(Pointer::parse(&[0xff, 0xfe]), None);
+    }
+
+    #[test]
+    fn derives_endpoint_from_remote() {
+        let workdir = temporary_dir();
+        let cases = [
+            (
+                "https://github.com/owner/repo",
+                "https://github.com/owner/repo.git/info/lfs",
+            ),
+            (
+                "https://github.com/owner/repo.git/",
+                "https://github.com/owner/repo.git/info/lfs",
+            ),
+            (
+                "ssh://git@github.com/owner/repo.git",
+                "https://github.com/owner/repo.git/info/lfs",
+            ),
+            (
+                "ssh://git@github.com:22/owner/repo",
+                "https://github.com/owner/repo.git/info/lfs",
+            ),
+            (
+                "git@github.com:owner/repo.git",
+                "https://github.com/owner/repo.git/info/lfs",
+            ),
+            (
+                "git://example.com/owner/repo.git",
+                "https://example.com/owner/repo.git/info/lfs",
+            ),
+        ];
+        for (remote, expected) in cases {
+            assert_eq!(endpoint(&workdir, remote).unwrap(), expected, "{}", remote);
+        }
+        assert!(endpoint(&workdir, "/local/path").is_err());
+
+        fs::remove_dir_all(&workdir).unwrap();
+    }
+
+    #[test]
+    fn prefers_configured_endpoint() {
+        let workdir = temporary_dir();
+        fs::write(
+            workdir.join(".lfsconfig"),
+            "[lfs]\n\turl = https://lfs.example.com/owner/repo/\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            endpoint(&workdir, "https://github.com/owner/repo").unwrap(),
+            "https://lfs.example.com/owner/repo"
+        );
+
+        fs::remove_dir_all(&workdir).unwrap();
+    }
+
+    #[test]
+    fn compares_servers() {
+        let remote = "https://github.com/owner/repo.git/info/lfs";
+        assert!(same_server("https://GitHub.com:443/other", remote));
+        assert!(!same_server(
+            "http://github.com/owner/repo.git/info/lfs",
+            remote
+        ));
+        assert!(!same_server("https://github.com.evil.com/", remote));
+        assert!(!same_server("https://github.com:8443/", remote));
+        assert!(!same_server("not a url", remote));
+    }
+
+    /// The method, path and authorization header of each request to the stand-in
+    type Requests = Arc<Mutex<Vec<(String, String, Option<String>)>>>;
+
+    /// Start a stand-in LFS server on a random port, offering `CONTENT` for
+    /// every object in a batch request along with an object that wasn't
+    /// requested, returning its URL
+    fn serve() -> (String, Requests) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Requests::default();
+
+        let (base, recorded) = (url.clone(), requests.clone());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let mut parts = line.split_whitespace();
+                let method = parts.next().unwrap().to_string();
+                let path = parts.next().unwrap().to_string();
+
+                let (mut length, mut authorization) = (0, None);
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    let header = header.trim_end();
+                    if header.is_empty() {
+                        break;
+                    }
+                    let (name, value) = header.split_once(": ").unwrap();
+                    match name.to_ascii_lowercase().as_str() {
+                        "content-length" => length = value.parse().unwrap(),
+                        "authorization" => authorization = Some(value.to_string()),
+                        _ => {}
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                recorded
+                    .lock()
+                    .unwrap()
+                    .push((method.clone(), path.clone(), authorization));
+
+                let (content_type, response) = if path.ends_with("/objects/batch") {
+                    let request: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                    let objects = request["objects"]
+                        .as_array()
+                        .unwrap()
+                        .iter()
+                        .map(|o| {
+                            let href = format!("{}/objects/{}", base, o["oid"].as_str().unwrap());
+                            serde_json::json!({
+                                "oid": o["oid"],
+                                "size": o["size"],
+                                "actions": { "download": { "href": href } },
+                            })
+                        })
+                        .chain([serde_json::json!({
+                            "oid": "../../escaped",
+                            "size": CONTENT.len(),
+                            "actions": { "download": { "href": format!("{}/escaped", base) } },
+                        })])
+                        .collect::<Vec<_>>();
+                    let response = serde_json::json!({ "objects": objects });
+                    (MEDIA_TYPE, serde_json::to_vec(&response).unwrap())
+                } else {
+                    ("application/octet-stream", CONTENT.to_vec())
+                };
+
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n",
+                    content_type,
+                    response.len()
+                )
+                .unwrap();
+                stream.write_all(b"Connection: close\r\n\r\n").unwrap();
+                stream.write_all(&response).unwrap();
+            }
+        });
+
+        (url, requests)
+    }
+
+    /// Create a repository whose only commit has a pointer to `CONTENT` and
+    /// an `.lfsconfig` pointing at the LFS server
+    fn repository(lfs_url: &str) -> (PathBuf, Repository) {
+        let workdir = temporary_dir();
+        let repo = Repository::init(&workdir).unwrap();
+
+        let oid = hash(CONTENT).unwrap();
+        fs::write(
+            workdir.join("asset.bin"),
+            pointer_text(&oid, CONTENT.len() as u64),
+        )
+        .unwrap();
+        fs::write(
+            workdir.join(".lfsconfig"),
+            format!("[lfs]\n\turl = {}\n", lfs_url),
+        )
+        .unwrap();
+
+        {
+            let mut index = repo.index().unwrap();
+            index
+                .add_all(["*"].iter(), git2::IndexAddOption::DEFAULT, None)
+                .unwrap();
+            let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+            let signature = Signature::now("test", "test@localhost").unwrap();
+            repo.commit(Some("HEAD"), &signature, &signature, "lfs", &tree, &[])
+                .unwrap();
+        }
+
+        (workdir, repo)
+    }
+
+    #[test]
+    fn downloads_objects() {
+        let (url, requests) = serve();
+        let (workdir, repo) = repository(&format!("{}/owner/repo.git/info/lfs", url));
+
+        let remote = format!("{}/owner/repo.git", url);
+        assert_eq!(checkout(&repo, &remote, Some("secret")).unwrap(), 1);
+        assert_eq!(fs::read(workdir.join("asset.bin")).unwrap(), CONTENT);
+
+        // The server is the repository's own, so the batch request is authenticated,
+        // and the object it offered that wasn't requested is never downloaded
+        let recorded = requests.lock().unwrap();
+        assert_eq!(recorded.len(), 2);
+        assert_eq!(recorded[0].0, "POST");
+        assert_eq!(recorded[0].1, "/owner/repo.git/info/lfs/objects/batch");
+        assert_eq!(
+            recorded[0].2.as_deref(),
+            Some(format!("Basic {}", base64::encode("x-access-token:secret")).as_str())
+        );
+        assert_eq!(recorded[1].0, "GET");
+        assert_eq!(
+            recorded[1].1,
+            format!("/objects/{}", hash(CONTENT).unwrap())
+        );
+        assert_eq!(recorded[1].2, None);
+        drop(recorded);
+
+        // Objects are only downloaded once
+        fs::write(workdir.join("asset.bin"), "changed").unwrap();
+        assert_eq!(checkout(&repo, &remote, Some("secret")).unwrap(), 1);
+        assert_eq!(fs::read(workdir.join("asset.bin")).unwrap(), CONTENT);
+        assert_eq!(requests.lock().unwrap().len(), 2);
+
+        fs::remove_dir_all(&workdir).unwrap();
+    }
+
+    #[test]
+    fn keeps_token_from_other_servers() {
+        let (url, requests) = serve();
+        let (workdir, repo) = repository(&format!("{}/lfs", url));
+
+        let remote = "https://github.com/owner/repo.git";
+        assert_eq!(checkout(&repo, remote, Some("secret")).unwrap(), 1);
+        assert_eq!(fs::read(workdir.join("asset.bin")).unwrap(), CONTENT);
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].1, "/lfs/objects/batch");
+        assert!(requests.iter().all(|(_, _, auth)| auth.is_none()));
+
+        fs::remove_dir_all(&workdir).unwrap();
+    }
+}
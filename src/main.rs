@@ -1,4 +1,5 @@
 use anyhow::{Context, Result};
+use chrono::Utc;
 use structopt::StructOpt;
 use tokio::fs;
 use tracing::Span;
@@ -8,14 +9,18 @@ use warp::{
     Filter,
 };
 
+mod api;
 mod args;
 mod config;
 mod github;
 mod http;
+mod keys;
+mod lfs;
 mod processor;
 mod repo;
+mod rollback;
 
-use args::Args;
+use args::{Args, Command};
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -26,7 +31,28 @@ async fn main() -> Result<()> {
     let configuration = config::parse(cli.config)
         .await
         .context("Failed to load configuration")?;
+
     let address = cli.address.unwrap_or(configuration.server.address);
+
+    // Run any management commands instead of the server
+    match cli.command {
+        Some(Command::Keys(command)) => return keys::run(command, &configuration.credentials),
+        Some(Command::Rollback {
+            repository,
+            target,
+            to,
+        }) => {
+            let request = rollback::Request {
+                repository,
+                timestamp: Utc::now().timestamp(),
+                target,
+                to,
+            };
+            return rollback::run(address, &configuration.server.secret, &request).await;
+        }
+        None => {}
+    }
+
     let log_filter = cli
         .log_level
         .unwrap_or_else(|| configuration.server.log.clone());
@@ -44,11 +70,21 @@ async fn main() -> Result<()> {
         .with_span_events(FmtSpan::CLOSE)
         .init();
 
+    // Connect to the GitHub API
+    let api = api::Client::new(&configuration.github).context("Failed to create API client")?;
+
     // Create the processing runner
-    let sender = processor::create(configuration.server.workers);
+    let queue = processor::create(
+        &configuration.server.pools,
+        &configuration.server.journal,
+        configuration.credentials.clone(),
+        configuration.git.clone(),
+        api.clone(),
+    )
+    .context("Failed to create deployment processor")?;
 
     // Setup the routes and launch the server
-    let routes = http::routes(configuration, sender)
+    let routes = http::routes(configuration, api, queue)
         .recover(http::recover)
         .with(trace_request());
     warp::serve(routes).run(address).await;
@@ -1,28 +1,72 @@
-use anyhow::{Context, Result};
+use super::{Kind, Message, Variables};
+use anyhow::{bail, Context, Result};
 use serde::Deserialize;
-use std::path::{Path, PathBuf};
+use std::{collections::HashMap, path::Path};
 use tokio::fs;
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
+    #[serde(default)]
     deploy: Vec<Action>,
+    #[serde(default)]
+    preview: Vec<Action>,
+    #[serde(default)]
+    teardown: Vec<Action>,
+    /// Run after a deployment is cancelled
+    #[serde(default)]
+    cleanup: Vec<Action>,
+    #[serde(default)]
+    environments: HashMap<String, Environment>,
 }
 
 impl Config {
     /// Parse the configuration from the repository
-    pub async fn parse(path: &Path) -> Result<Vec<Action>> {
+    pub async fn parse(path: &Path) -> Result<Self> {
         // Read and parse the config
         let content = fs::read(path).await.context("failed to find config")?;
-        let config: Config = toml::from_slice(&content).context("invalid config format")?;
+        toml::from_slice(&content).context("invalid config format")
+    }
+
+    /// Take the actions to run after a deployment is cancelled
+    pub fn cleanup(&mut self) -> Vec<Action> {
+        std::mem::take(&mut self.cleanup)
+    }
+
+    /// Get the actions and variables for the deployment, ensuring
+    /// it is allowed to deploy to the environment
+    pub fn resolve(mut self, message: &Message) -> Result<(Vec<Action>, Variables)> {
+        // Only get the actions for the kind of deployment
+        let actions = match (message.kind, &message.environment) {
+            (Kind::Deploy, Some(name)) => match self.environments.remove(name) {
+                Some(environment) => {
+                    // Variables from the event take precedence
+                    let mut variables = Variables::from(environment.variables);
+                    variables.extend(&message.variables);
+                    return Ok((environment.deploy, variables));
+                }
+                None => bail!("environment {} is not configured", name),
+            },
+            (Kind::Deploy, None) => self.deploy,
+            (Kind::Preview, _) => self.preview,
+            (Kind::Teardown, _) => self.teardown,
+        };
 
-        // Only get the deployment actions
-        Ok(config.deploy)
+        Ok((actions, message.variables.clone()))
     }
 }
 
+/// The actions and variables for deploying to a specific environment
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Environment {
+    deploy: Vec<Action>,
+    #[serde(default)]
+    variables: HashMap<String, String>,
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(tag = "action", rename_all = "lowercase")]
 pub enum Action {
     Command { command: String, args: Vec<String> },
-    Copy { src: PathBuf, dest: PathBuf },
+    Copy { src: String, dest: String },
 }
@@ -0,0 +1,138 @@
+use super::{release, Message, Update, Variables};
+use crate::repo;
+use anyhow::Result;
+use git2::{Oid, Repository};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, path::Path};
+use uuid::Uuid;
+
+/// The reference whose log records every successful deploy of the checkout
+const REFERENCE: &str = "refs/autodeploy/deployed";
+
+/// The reference whose log records the local changes found in the checkout
+const LOCAL_CHANGES: &str = "refs/autodeploy/local-changes";
+
+/// A successful deploy, stored as the message of its entry in the reference's log
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Deployed {
+    pub id: Uuid,
+    pub reference: Option<String>,
+    pub environment: Option<String>,
+    pub deployment: Option<u64>,
+    /// How many releases were kept, `None` if deployed in the checkout
+    #[serde(default)]
+    pub releases: Option<u32>,
+    #[serde(default)]
+    pub pool: Option<String>,
+    #[serde(default)]
+    pub installation: Option<u64>,
+    #[serde(default)]
+    pub variables: Variables,
+    /// The commit that was live before rolling back to this one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rolled_back_from: Option<String>,
+}
+
+/// Files changed in the checkout outside of deploys, found while updating it for
+/// a deploy and stored as the message of an entry in the reference's log
+#[derive(Serialize)]
+struct LocalChanges<'a> {
+    id: Uuid,
+    files: &'a [String],
+}
+
+/// Get the successful deploys of the checkout, newest first
+pub fn load(repo: &Repository) -> Result<Vec<(Oid, Deployed)>, git2::Error> {
+    let entries = repo::reference_log(repo, REFERENCE)?
+        .into_iter()
+        .filter_map(|(commit, message)| Some((commit, serde_json::from_str(&message).ok()?)))
+        .collect();
+    Ok(entries)
+}
+
+/// Find the deploy to roll back to, either by its commit or GitHub deployment,
+/// or the newest one before the live commit that wasn't itself rolled back
+pub fn find(history: Vec<(Oid, Deployed)>, to: Option<&str>) -> Option<(Oid, Deployed)> {
+    match to {
+        Some(to) => {
+            let to = to.to_ascii_lowercase();
+            let by_deployment = history
+                .iter()
+                .position(|(_, d)| d.deployment.map(|id| id.to_string()).as_ref() == Some(&to));
+            let by_commit = || {
+                history
+                    .iter()
+                    .position(|(c, _)| c.to_string().starts_with(&to))
+            };
+            let index = by_deployment.or_else(by_commit)?;
+            history.into_iter().nth(index)
+        }
+        None => {
+            // Commits are only skipped if they were rolled back after they were deployed
+            let current = history.first()?.0;
+            let mut rolled_back = HashSet::new();
+            for (commit, deployed) in history {
+                if commit != current && !rolled_back.contains(&commit.to_string()) {
+                    return Some((commit, deployed));
+                }
+                rolled_back.extend(deployed.rolled_back_from);
+            }
+            None
+        }
+    }
+}
+
+/// Find the deploy the checkout at the path would be rolled back to
+pub async fn lookup(root: &Path, to: Option<String>) -> Option<Deployed> {
+    let checkout = release::checkout(root);
+    tokio::task::spawn_blocking(move || {
+        let repo = Repository::open(checkout).ok()?;
+        let (_, deployed) = find(load(&repo).ok()?, to.as_deref())?;
+        Some(deployed)
+    })
+    .await
+    .unwrap()
+}
+
+/// Record the commit checked out by the message as successfully deployed
+pub async fn record(message: &Message) -> Result<()> {
+    let checkout = message.checkout();
+    let rollback = matches!(message.update, Update::Rollback { .. });
+    let mut deployed = Deployed {
+        id: message.id,
+        reference: message.reference.clone(),
+        environment: message.environment.clone(),
+        deployment: message.deployment,
+        releases: message.releases,
+        pool: message.pool.clone(),
+        installation: message.installation,
+        variables: message.variables.clone(),
+        rolled_back_from: None,
+    };
+
+    tokio::task::spawn_blocking(move || {
+        let repo = Repository::open(checkout)?;
+        let commit = repo.head()?.peel_to_commit()?.id();
+        if rollback {
+            deployed.rolled_back_from = load(&repo)?.first().map(|(c, _)| c.to_string());
+        }
+
+        repo::log_reference(&repo, REFERENCE, commit, &serde_json::to_string(&deployed)?)?;
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+/// Record the local changes found in the checkout against the commit they were
+/// made on, whether or not the deploy goes ahead
+pub fn record_local_changes(
+    repo: &Repository,
+    id: Uuid,
+    files: &[String],
+) -> Result<(), git2::Error> {
+    let commit = repo.head()?.peel_to_commit()?.id();
+    let entry = serde_json::to_string(&LocalChanges { id, files })
+        .expect("local changes are always serializable");
+    repo::log_reference(repo, LOCAL_CHANGES, commit, &entry)
+}
@@ -0,0 +1,260 @@
+use super::Message;
+use crate::config;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    sync::Mutex,
+};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+/// How many entries of finished deployments the journal can hold before it is
+/// rewritten with only the outstanding ones
+const COMPACT_THRESHOLD: usize = 1000;
+
+/// A change in the state of a deployment, stored as a line of JSON
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "state", rename_all = "lowercase")]
+enum Entry<M> {
+    Queued { message: M },
+    Running { id: Uuid },
+    Finished { id: Uuid },
+}
+
+/// An append-only record of the queued and running deployments,
+/// allowing them to be recovered after a restart
+pub struct Journal {
+    state: Mutex<State>,
+}
+
+struct State {
+    path: PathBuf,
+    file: File,
+    /// The deployments that have not finished yet
+    outstanding: HashMap<Uuid, Outstanding>,
+    /// How many entries were written since the journal was last rewritten
+    entries: usize,
+    /// The order the next queued deployment is rewritten in
+    sequence: u64,
+}
+
+/// The entries a deployment that has not finished yet is rewritten with
+struct Outstanding {
+    sequence: u64,
+    /// Its queued entry, as written to the journal
+    queued: String,
+    running: bool,
+}
+
+/// The deployments recovered from the journal
+#[derive(Default)]
+pub struct Replay {
+    /// Deployments to queue again, in their original order
+    pub queued: Vec<Message>,
+    /// Deployments that were interrupted and will not be retried
+    pub interrupted: Vec<Message>,
+}
+
+impl Journal {
+    /// Open the journal, recovering any deployments that did not finish
+    pub fn open(config: &config::Journal) -> Result<(Self, Replay)> {
+        let replay = if config.path.exists() {
+            let file = File::open(&config.path).context("failed to open journal")?;
+            replay(BufReader::new(file), config.retries)
+        } else {
+            Replay::default()
+        };
+
+        // Compact the journal down to the deployments being requeued
+        let mut outstanding = HashMap::new();
+        for (sequence, message) in (0..).zip(&replay.queued) {
+            let queued = serde_json::to_string(&Entry::Queued { message })?;
+            let entry = Outstanding {
+                sequence,
+                queued,
+                running: false,
+            };
+            outstanding.insert(message.id, entry);
+        }
+        let mut state = State {
+            path: config.path.clone(),
+            file: OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&config.path)
+                .context("failed to open journal")?,
+            entries: 0,
+            sequence: outstanding.len() as u64,
+            outstanding,
+        };
+        state.rewrite().context("failed to write journal")?;
+
+        let journal = Self {
+            state: Mutex::new(state),
+        };
+        Ok((journal, replay))
+    }
+
+    /// Record that a deployment was queued
+    pub fn queued(&self, message: &Message) {
+        let mut state = self.state.lock().unwrap();
+        let queued = serde_json::to_string(&Entry::Queued { message })
+            .expect("entries are always serializable");
+        state.append(&queued);
+
+        let entry = Outstanding {
+            sequence: state.sequence,
+            queued,
+            running: false,
+        };
+        state.sequence += 1;
+        state.outstanding.insert(message.id, entry);
+    }
+
+    /// Record that a deployment started running
+    pub fn running(&self, message: &Message) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(entry) = state.outstanding.get_mut(&message.id) {
+            entry.running = true;
+        }
+        state.append_entry(&Entry::Running { id: message.id });
+    }
+
+    /// Record that a deployment finished, was skipped or was cancelled
+    pub fn finished(&self, message: &Message) {
+        let mut state = self.state.lock().unwrap();
+        state.outstanding.remove(&message.id);
+
+        // Start over once nothing is outstanding to keep the journal small
+        if state.outstanding.is_empty() {
+            if let Err(e) = state.file.set_len(0) {
+                error!(error = %e, "failed to truncate journal");
+            }
+            state.entries = 0;
+            return;
+        }
+        state.append_entry(&Entry::Finished { id: message.id });
+
+        // Deployments may be outstanding at every point under steady traffic,
+        // so also rewrite the journal once enough of it is finished ones
+        let live = state
+            .outstanding
+            .values()
+            .map(|e| 1 + e.running as usize)
+            .sum::<usize>();
+        if state.entries - live >= COMPACT_THRESHOLD {
+            info!("compacting journal");
+            if let Err(e) = state.rewrite() {
+                error!(error = %e, "failed to compact journal");
+            }
+        }
+    }
+}
+
+impl State {
+    fn append_entry(&mut self, entry: &Entry<&Message>) {
+        let line = serde_json::to_string(entry).expect("entries are always serializable");
+        self.append(&line);
+    }
+
+    fn append(&mut self, line: &str) {
+        self.entries += 1;
+        if let Err(e) = writeln!(self.file, "{}", line) {
+            error!(error = %e, "failed to write to journal");
+        }
+    }
+
+    /// Replace the journal with the entries of the deployments that have not
+    /// finished yet, in the order they were queued
+    fn rewrite(&mut self) -> std::io::Result<()> {
+        let mut outstanding = self.outstanding.iter().collect::<Vec<_>>();
+        outstanding.sort_by_key(|(_, entry)| entry.sequence);
+
+        let temporary = self.path.with_extension("tmp");
+        let mut entries = 0;
+        {
+            let mut file = File::create(&temporary)?;
+            for (&id, entry) in outstanding {
+                writeln!(file, "{}", entry.queued)?;
+                entries += 1;
+                if entry.running {
+                    let running = Entry::<&Message>::Running { id };
+                    let line =
+                        serde_json::to_string(&running).expect("entries are always serializable");
+                    writeln!(file, "{}", line)?;
+                    entries += 1;
+                }
+            }
+            file.sync_all()?;
+        }
+        fs::rename(&temporary, &self.path)?;
+
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.entries = entries;
+        Ok(())
+    }
+}
+
+/// Find the deployments that did not finish before the journal was closed,
+/// retrying the interrupted ones up to the given number of times
+fn replay<R: BufRead>(reader: R, retries: u32) -> Replay {
+    let mut order = Vec::new();
+    let mut messages = HashMap::new();
+    let mut running = HashSet::new();
+
+    for line in reader.lines() {
+        // The last line may be incomplete if the process was killed mid-write
+        let entry = match line.map(|l| serde_json::from_str::<Entry<Message>>(&l)) {
+            Ok(Ok(entry)) => entry,
+            Ok(Err(e)) => {
+                warn!(error = %e, "skipping invalid journal entry");
+                continue;
+            }
+            Err(e) => {
+                error!(error = %e, "failed to read journal");
+                break;
+            }
+        };
+
+        match entry {
+            Entry::Queued { message } => {
+                order.push(message.id);
+                messages.insert(message.id, message);
+            }
+            Entry::Running { id } => {
+                if let Some(message) = messages.get_mut(&id) {
+                    message.attempts += 1;
+                    running.insert(id);
+                }
+            }
+            Entry::Finished { id } => {
+                messages.remove(&id);
+            }
+        }
+    }
+
+    let mut replay = Replay::default();
+    for id in order {
+        let message = match messages.remove(&id) {
+            Some(m) => m,
+            None => continue,
+        };
+
+        if !running.contains(&id) {
+            info!(id = %id, repository = %&message.repository, "requeueing deploy");
+            replay.queued.push(message);
+        } else if message.attempts <= retries {
+            warn!(id = %id, repository = %&message.repository, "deploy was interrupted, retrying");
+            replay.queued.push(message);
+        } else {
+            warn!(id = %id, repository = %&message.repository, "deploy was interrupted");
+            replay.interrupted.push(message);
+        }
+    }
+
+    replay
+}
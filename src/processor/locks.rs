@@ -0,0 +1,56 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::{futures::Notified, Notify};
+
+/// Ensures only one deployment of each repository runs at a time, while
+/// different repositories still deploy in parallel
+#[derive(Clone, Default)]
+pub struct Locks {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// The repositories with a deployment running
+    busy: Mutex<HashSet<String>>,
+    /// Notified whenever a repository is released
+    released: Notify,
+}
+
+impl Locks {
+    /// Take the repository if no other deployment of it is running
+    pub fn try_lock(&self, repository: &str) -> Option<Guard> {
+        // Repository names on GitHub are case-insensitive
+        let repository = repository.to_ascii_lowercase();
+        let mut busy = self.inner.busy.lock().unwrap();
+        if !busy.insert(repository.clone()) {
+            return None;
+        }
+
+        Some(Guard {
+            locks: self.clone(),
+            repository,
+        })
+    }
+
+    /// Wait until any repository is released
+    pub fn released(&self) -> Notified<'_> {
+        self.inner.released.notified()
+    }
+}
+
+/// Exclusive access to a repository, released when dropped
+pub struct Guard {
+    locks: Locks,
+    repository: String,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        let inner = &self.locks.inner;
+        inner.busy.lock().unwrap().remove(&self.repository);
+        inner.released.notify_waiters();
+    }
+}
@@ -1,18 +1,204 @@
-use async_channel::Sender;
-use std::path::PathBuf;
+use super::{release, Queue, Variables};
+use crate::config::{Paths, Priority};
+use serde::{Deserialize, Serialize};
+use std::{path::PathBuf, time::Duration};
+use uuid::Uuid;
 
 /// The message to be sent from the webhook handler
 /// to the deployment processor containing the necessary
 /// information to deploy the repository.
-#[derive(Debug)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Message {
+    pub id: Uuid,
     pub path: PathBuf,
     pub repository: String,
+    pub kind: Kind,
+    /// How to update the local copy before running the actions
+    pub update: Update,
+    /// The branch, tag or other reference that was deployed
+    pub reference: Option<String>,
+    /// The environment whose actions should be run
+    pub environment: Option<String>,
+    /// The GitHub deployment to report the status to
+    pub deployment: Option<u64>,
+    /// The variables available to the actions
+    pub variables: Variables,
+    /// How long to wait for newer deploys of the same checkout before queueing
+    pub debounce: Duration,
+    /// Whether to cancel the running deploy of the same checkout
+    pub cancel_in_progress: bool,
+    /// How many times the deploy has been started
+    pub attempts: u32,
+    /// The worker pool to deploy with, the default pool if `None`
+    pub pool: Option<String>,
+    /// The GitHub App installation to fetch the repository as
+    pub installation: Option<u64>,
+    #[serde(default)]
+    pub priority: Priority,
+    /// Files changed in the checkout outside of deploys, found while updating
+    #[serde(default)]
+    pub local_changes: Vec<String>,
+    /// How many releases to keep when deploying into a new directory each time,
+    /// deploying in the checkout if `None`
+    #[serde(default)]
+    pub releases: Option<u32>,
 }
 
 impl Message {
-    /// Send a new message
-    pub async fn send(sender: Sender<Self>, path: PathBuf, repository: String) {
-        sender.send(Self { path, repository }).await.unwrap();
+    /// Create a new message
+    pub fn new(path: PathBuf, repository: String, kind: Kind) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            path,
+            repository,
+            kind,
+            update: Update::None,
+            reference: None,
+            environment: None,
+            deployment: None,
+            variables: Variables::default(),
+            debounce: Duration::ZERO,
+            cancel_in_progress: false,
+            attempts: 0,
+            pool: None,
+            installation: None,
+            priority: Priority::default(),
+            local_changes: Vec::new(),
+            releases: None,
+        }
     }
+
+    /// Where the local copy of the repository is kept, which is next to the
+    /// releases if they are enabled
+    pub fn checkout(&self) -> PathBuf {
+        match self.releases {
+            Some(_) => self.path.join(release::CHECKOUT),
+            None => self.path.clone(),
+        }
+    }
+
+    /// Set how to update the local copy
+    pub fn update(mut self, update: Update) -> Self {
+        self.update = update;
+        self
+    }
+
+    /// Set the environment to deploy to
+    pub fn environment(mut self, environment: Option<String>) -> Self {
+        self.environment = environment;
+        self
+    }
+
+    /// Set the GitHub deployment to report the status to
+    pub fn deployment(mut self, id: Option<u64>) -> Self {
+        self.deployment = id;
+        self
+    }
+
+    /// Set the variables available to the actions
+    pub fn variables(mut self, variables: Variables) -> Self {
+        self.variables = variables;
+        self
+    }
+
+    /// Set how long to wait for newer deploys before queueing
+    pub fn debounce(mut self, seconds: u64) -> Self {
+        self.debounce = Duration::from_secs(seconds);
+        self
+    }
+
+    /// Set whether to cancel the running deploy of the same checkout
+    pub fn cancel_in_progress(mut self, cancel: bool) -> Self {
+        self.cancel_in_progress = cancel;
+        self
+    }
+
+    /// Set the worker pool to deploy with
+    pub fn pool(mut self, pool: Option<String>) -> Self {
+        self.pool = pool;
+        self
+    }
+
+    /// Set the GitHub App installation to fetch the repository as
+    pub fn installation(mut self, installation: Option<u64>) -> Self {
+        self.installation = installation;
+        self
+    }
+
+    /// Set how soon to deploy relative to the rest of the pool's queue
+    pub fn priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Set how many releases to keep, deploying into a new directory each time
+    pub fn releases(mut self, releases: Option<u32>) -> Self {
+        self.releases = releases;
+        self
+    }
+
+    /// Send the message
+    pub async fn send(self, queue: &Queue) {
+        queue.push(self).await;
+    }
+}
+
+/// The set of actions to run
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub enum Kind {
+    /// Deploy the repository
+    Deploy,
+    /// Deploy a preview of a pull request
+    Preview,
+    /// Remove a pull request's preview
+    Teardown,
+}
+
+/// How the local copy of the repository is updated before deploying
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum Update {
+    /// Fetch the reference and optionally check out a specific commit
+    Fetch {
+        clone_url: String,
+        ssh_url: Option<String>,
+        refspec: String,
+        commit: Option<String>,
+        changes: Option<Box<Changes>>,
+    },
+    /// Revert to the release before the given tag
+    Revert { tag: String },
+    /// Return to an earlier successful deploy, chosen by its commit or GitHub
+    /// deployment, or the one before the live commit if `None`
+    Rollback { to: Option<String> },
+    /// Use the local copy as is
+    None,
+}
+
+impl Update {
+    /// Take over the pushed changes of an older update this one supersedes,
+    /// so the commits it would have deployed are still checked
+    pub fn supersede(&mut self, older: &Update) {
+        if let (Update::Fetch { changes, .. }, Update::Fetch { changes: older, .. }) = (self, older)
+        {
+            match (changes.as_mut(), older) {
+                (Some(changes), Some(older)) => {
+                    changes.before = older.before.clone();
+                    if older.paths.is_none() {
+                        changes.paths = None;
+                    }
+                }
+                // The older update was deployed whatever it changed
+                (Some(_), None) => *changes = None,
+                (None, _) => {}
+            }
+        }
+    }
+}
+
+/// The pushed changes to check once the repository is updated
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Changes {
+    pub before: String,
+    pub paths: Option<Paths>,
+    pub skip_markers: Vec<String>,
 }
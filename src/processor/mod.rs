@@ -1,23 +1,70 @@
-use async_channel::Sender;
+use crate::{
+    api::Client,
+    config::{Credentials, Git, Journal},
+};
+use anyhow::Result;
+use std::{collections::HashMap, sync::Arc};
 use tracing::info;
 
 mod config;
+mod history;
+mod journal;
+mod locks;
 mod message;
+mod pool;
+mod queue;
+mod release;
+mod update;
+mod variables;
 mod worker;
 
-pub use message::Message;
+pub use history::lookup as find_deployed;
+pub use message::{Changes, Kind, Message, Update};
+pub use queue::Queue;
+pub use variables::Variables;
 
-/// Create a new deployment processor
-pub fn create(num_workers: u32) -> Sender<Message> {
-    // Create the channels
-    let (tx, rx) = async_channel::unbounded();
+/// Create a new deployment processor, recovering any deployments
+/// queued before the last shutdown
+pub fn create(
+    pools: &HashMap<String, u32>,
+    journal: &Journal,
+    credentials: Credentials,
+    git: Git,
+    api: Client,
+) -> Result<Queue> {
+    let (journal, replay) = journal::Journal::open(journal)?;
 
-    info!(count = num_workers, "spawning deployment workers");
+    // Create the pools
+    let pools = pools
+        .iter()
+        .map(|(name, &size)| (name.clone(), Arc::new(pool::Pool::new(size))))
+        .collect::<HashMap<_, _>>();
+    let queue = Queue::new(pools.clone(), api.clone(), journal);
 
-    // Spawn the workers
-    for id in 0..num_workers {
-        tokio::spawn(worker::worker(id, rx.clone()));
+    // Spawn the workers for each pool
+    let locks = locks::Locks::default();
+    let credentials = Arc::new(credentials);
+    let git = Arc::new(git);
+    let mut id = 0;
+    for (name, pool) in pools {
+        info!(pool = %name, count = pool.size(), "spawning deployment workers");
+        for _ in 0..pool.size() {
+            tokio::spawn(worker::worker(
+                id,
+                pool.clone(),
+                queue.clone(),
+                locks.clone(),
+                credentials.clone(),
+                git.clone(),
+                api.clone(),
+            ));
+            id += 1;
+        }
     }
 
-    tx
+    // Queue the deployments from before the restart
+    let requeue = queue.clone();
+    tokio::spawn(async move { requeue.replay(replay).await });
+
+    Ok(queue)
 }
@@ -0,0 +1,113 @@
+use super::{
+    locks::{Guard, Locks},
+    Message,
+};
+use crate::config::Priority;
+use serde::Serialize;
+use std::{
+    cmp::Reverse,
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU32, Ordering as AtomicOrdering},
+        Mutex,
+    },
+};
+use tokio::sync::Notify;
+
+/// A named group of workers with its own queue, running the highest
+/// priority deploys first and otherwise in the order they were queued
+pub struct Pool {
+    size: u32,
+    queued: Mutex<Queued>,
+    notify: Notify,
+    busy: AtomicU32,
+}
+
+#[derive(Default)]
+struct Queued {
+    /// Ordered so the last message is the next to run
+    messages: BTreeMap<Key, Message>,
+    sequence: u64,
+}
+
+/// Orders messages by priority then by when they were queued
+type Key = (Priority, Reverse<u64>);
+
+/// The state of a pool, as shown by the status API
+#[derive(Serialize)]
+pub struct Status {
+    pub workers: u32,
+    pub queued: usize,
+    pub running: u32,
+}
+
+impl Pool {
+    /// Create a new pool with the given number of workers
+    pub fn new(size: u32) -> Self {
+        Self {
+            size,
+            queued: Mutex::default(),
+            notify: Notify::new(),
+            busy: AtomicU32::new(0),
+        }
+    }
+
+    /// The number of workers in the pool
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Add a message to the queue
+    pub fn push(&self, message: Message) {
+        {
+            let mut queued = self.queued.lock().unwrap();
+            queued.sequence += 1;
+            let key = (message.priority, Reverse(queued.sequence));
+            queued.messages.insert(key, message);
+        }
+        self.notify.notify_one();
+    }
+
+    /// Wait for the next message whose repository isn't being deployed,
+    /// taking the lock on the repository at the same time. Messages for busy
+    /// repositories stay queued so they don't hold up the other workers.
+    pub async fn pop(&self, locks: &Locks) -> (Message, Guard) {
+        loop {
+            // Register for wakeups before checking to avoid missing one
+            let notified = self.notify.notified();
+            let released = locks.released();
+            {
+                let mut queued = self.queued.lock().unwrap();
+                let next = queued.messages.iter().rev().find_map(|(key, message)| {
+                    locks
+                        .try_lock(&message.repository)
+                        .map(|guard| (*key, guard))
+                });
+                if let Some((key, guard)) = next {
+                    let message = queued.messages.remove(&key).unwrap();
+                    self.busy.fetch_add(1, AtomicOrdering::SeqCst);
+                    return (message, guard);
+                }
+            }
+
+            tokio::select! {
+                _ = notified => {}
+                _ = released => {}
+            }
+        }
+    }
+
+    /// Mark a message taken from the queue as done
+    pub fn done(&self) {
+        self.busy.fetch_sub(1, AtomicOrdering::SeqCst);
+    }
+
+    /// Get the current state of the pool
+    pub fn status(&self) -> Status {
+        Status {
+            workers: self.size,
+            queued: self.queued.lock().unwrap().messages.len(),
+            running: self.busy.load(AtomicOrdering::SeqCst),
+        }
+    }
+}
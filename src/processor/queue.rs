@@ -0,0 +1,208 @@
+use super::{
+    journal::{Journal, Replay},
+    pool::{Pool, Status},
+    worker::report,
+    Message, Update,
+};
+use crate::{
+    api::{Client, DeploymentState},
+    config::DEFAULT_POOL,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::watch;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// Notifies a running deployment that a newer one cancelled it
+pub type Cancel = watch::Receiver<Option<Uuid>>;
+
+/// The repository and path of a checkout
+type Checkout = (String, PathBuf);
+
+/// How to cancel a running deployment and how it updates the checkout
+type Running = (watch::Sender<Option<Uuid>>, Update);
+
+/// The most recently queued message for a checkout. It is kept after the
+/// message starts so older messages still queued for the checkout are skipped.
+struct Latest {
+    id: Uuid,
+    /// How the message updates the checkout, until it starts
+    update: Option<Update>,
+}
+
+/// Queues messages for the workers, superseding any messages for the same
+/// checkout that have not started yet
+#[derive(Clone)]
+pub struct Queue {
+    pools: Arc<HashMap<String, Arc<Pool>>>,
+    api: Client,
+    journal: Arc<Journal>,
+    /// The most recently queued message for each checkout
+    latest: Arc<Mutex<HashMap<Checkout, Latest>>>,
+    /// The deployments currently running for each checkout
+    running: Arc<Mutex<HashMap<Checkout, Running>>>,
+}
+
+impl Queue {
+    /// Create a new queue sending to the workers of the pools
+    pub(super) fn new(pools: HashMap<String, Arc<Pool>>, api: Client, journal: Journal) -> Self {
+        Self {
+            pools: Arc::new(pools),
+            api,
+            journal: Arc::new(journal),
+            latest: Arc::default(),
+            running: Arc::default(),
+        }
+    }
+
+    /// Queue a message once its debounce window has passed, dropping it if
+    /// a newer message for the same checkout arrived in the meantime
+    pub async fn push(&self, mut message: Message) {
+        self.supersede(&mut message);
+        self.journal.queued(&message);
+
+        if message.debounce.is_zero() {
+            self.send(message);
+            return;
+        }
+
+        let queue = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(message.debounce).await;
+            match queue.superseded(&message) {
+                Some(by) => queue.skip(&message, by).await,
+                None => queue.send(message),
+            }
+        });
+    }
+
+    /// Queue the deployments recovered from the journal, reporting the
+    /// interrupted ones that will not be retried as failed
+    pub(super) async fn replay(&self, replay: Replay) {
+        for message in replay.interrupted {
+            report(&self.api, &message, DeploymentState::Error).await;
+        }
+
+        for mut message in replay.queued {
+            self.supersede(&mut message);
+            self.send(message);
+        }
+    }
+
+    /// Make the message the latest for its checkout, taking over the changes
+    /// of the queued message it replaces and of the running one it cancels
+    fn supersede(&self, message: &mut Message) {
+        let key = key(message);
+        if message.cancel_in_progress {
+            if let Some((_, update)) = self.running.lock().unwrap().get(&key) {
+                message.update.supersede(update);
+            }
+        }
+
+        let mut latest = self.latest.lock().unwrap();
+        if let Some(update) = latest.get(&key).and_then(|l| l.update.as_ref()) {
+            message.update.supersede(update);
+        }
+        latest.insert(
+            key,
+            Latest {
+                id: message.id,
+                update: Some(message.update.clone()),
+            },
+        );
+    }
+
+    /// Send the message to the workers, cancelling the running deployment
+    /// of the same checkout if requested
+    fn send(&self, message: Message) {
+        if message.cancel_in_progress {
+            if let Some((running, _)) = self.running.lock().unwrap().get(&key(&message)) {
+                info!(
+                    repository = %&message.repository,
+                    "cancelling running deploy in favor of {}",
+                    message.id
+                );
+                let _ = running.send(Some(message.id));
+            }
+        }
+
+        self.pool(&message).push(message);
+    }
+
+    /// Get the pool the message is deployed with, falling back to the
+    /// default pool if it no longer exists
+    fn pool(&self, message: &Message) -> &Pool {
+        let name = message.pool.as_deref().unwrap_or(DEFAULT_POOL);
+        match self.pools.get(name) {
+            Some(pool) => pool,
+            None => {
+                warn!(pool = %name, "unknown worker pool, using the default pool");
+                &self.pools[DEFAULT_POOL]
+            }
+        }
+    }
+
+    /// Get the state of each pool
+    pub fn status(&self) -> BTreeMap<&str, Status> {
+        self.pools
+            .iter()
+            .map(|(name, pool)| (name.as_str(), pool.status()))
+            .collect()
+    }
+
+    /// Mark the message as started, returning how to notify it of being
+    /// cancelled or `None` if it was superseded and should be skipped
+    pub(super) async fn start(&self, message: &Message) -> Option<Cancel> {
+        if let Some(by) = self.superseded(message) {
+            self.skip(message, by).await;
+            return None;
+        }
+
+        self.journal.running(message);
+        let key = key(message);
+        let (tx, rx) = watch::channel(None);
+        if let Some(latest) = self.latest.lock().unwrap().get_mut(&key) {
+            latest.update = None;
+        }
+        self.running
+            .lock()
+            .unwrap()
+            .insert(key, (tx, message.update.clone()));
+        Some(rx)
+    }
+
+    /// Mark the message as finished
+    pub(super) fn finish(&self, message: &Message) {
+        self.running.lock().unwrap().remove(&key(message));
+        self.journal.finished(message);
+    }
+
+    /// Get the message that superseded this one, if any
+    fn superseded(&self, message: &Message) -> Option<Uuid> {
+        match self.latest.lock().unwrap().get(&key(message)) {
+            Some(latest) if latest.id != message.id => Some(latest.id),
+            _ => None,
+        }
+    }
+
+    /// Record that the message was skipped in favor of a newer one
+    async fn skip(&self, message: &Message, by: Uuid) {
+        info!(
+            id = %message.id,
+            repository = %&message.repository,
+            "skipped (superseded by {})",
+            by
+        );
+        self.journal.finished(message);
+        report(&self.api, message, DeploymentState::Inactive).await;
+    }
+}
+
+/// Messages supersede each other when they deploy the same checkout
+fn key(message: &Message) -> Checkout {
+    (message.repository.clone(), message.path.clone())
+}
@@ -0,0 +1,156 @@
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+use git2::Repository;
+use std::{
+    fs, io,
+    os::unix::fs::symlink,
+    path::{Path, PathBuf},
+};
+use tracing::{info, warn};
+
+/// The directory containing every release
+const RELEASES: &str = "releases";
+
+/// The link to the release that is live
+const CURRENT: &str = "current";
+
+/// The local copy of the repository, kept next to the releases
+pub const CHECKOUT: &str = "repo";
+
+/// Export the commit checked out in the local copy into a new release
+/// directory, returning its path
+pub async fn create(root: &Path, checkout: &Path) -> Result<PathBuf> {
+    let root = root.to_owned();
+    let checkout = checkout.to_owned();
+
+    tokio::task::spawn_blocking(move || {
+        let repo = Repository::open(&checkout)?;
+        let commit = repo.head()?.peel_to_commit()?.id().to_string();
+
+        // Names sort in the order the releases were created, with microseconds
+        // so deploys of the same commit in quick succession don't collide
+        let name = format!("{}-{}", Utc::now().format("%Y%m%d%H%M%S%6f"), &commit[..12]);
+        let release = root.join(RELEASES).join(name);
+        if release.exists() {
+            bail!("release {} already exists", release.display());
+        }
+
+        // The working tree already has the LFS objects and submodules checked out
+        fs::create_dir_all(&release)?;
+        if let Err(e) = copy(&checkout, &release) {
+            let _ = fs::remove_dir_all(&release);
+            return Err(e).context("failed to export release");
+        }
+
+        info!(release = ?&release, "created release");
+        Ok(release)
+    })
+    .await
+    .unwrap()
+}
+
+/// Find the newest release of the commit checked out in the local copy
+pub async fn find(root: &Path, checkout: &Path) -> Result<Option<PathBuf>> {
+    let root = root.to_owned();
+    let checkout = checkout.to_owned();
+
+    tokio::task::spawn_blocking(move || {
+        let repo = Repository::open(&checkout)?;
+        let commit = repo.head()?.peel_to_commit()?.id().to_string();
+        let suffix = format!("-{}", &commit[..12]);
+
+        let mut releases = fs::read_dir(root.join(RELEASES))?
+            .map(|entry| Ok(entry?.path()))
+            .collect::<io::Result<Vec<_>>>()?;
+        releases.sort();
+        Ok(releases
+            .into_iter()
+            .rev()
+            .find(|r| r.to_string_lossy().ends_with(&suffix)))
+    })
+    .await
+    .unwrap()
+}
+
+/// Make the release live by swapping the `current` link over to it
+pub async fn activate(root: &Path, release: &Path) -> Result<()> {
+    let name = release.file_name().context("release has no name")?;
+    let target = Path::new(RELEASES).join(name);
+
+    // Renaming the new link over the old one replaces it atomically
+    let temporary = root.join(format!("{}.new", CURRENT));
+    let _ = tokio::fs::remove_file(&temporary).await;
+    tokio::fs::symlink(&target, &temporary).await?;
+    tokio::fs::rename(&temporary, root.join(CURRENT)).await?;
+
+    info!(release = ?release, "activated release");
+    Ok(())
+}
+
+/// Remove a release that was never made live
+pub async fn discard(release: &Path) {
+    match tokio::fs::remove_dir_all(release).await {
+        Ok(_) => info!(release = ?release, "removed release"),
+        Err(e) => warn!(release = ?release, error = %e, "failed to remove release"),
+    }
+}
+
+/// Remove the oldest releases until only `keep` are left, never removing the live one
+pub async fn prune(root: &Path, keep: u32) -> Result<()> {
+    let current = current(root).await;
+
+    let mut releases = Vec::new();
+    let mut entries = tokio::fs::read_dir(root.join(RELEASES)).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        releases.push(entry.path());
+    }
+    releases.sort();
+
+    let excess = releases.len().saturating_sub(keep as usize);
+    for release in releases.into_iter().take(excess) {
+        if Some(&release) != current.as_ref() {
+            discard(&release).await;
+        }
+    }
+
+    Ok(())
+}
+
+/// Find the local copy of the repository, which is next to the releases if
+/// the checkout deploys into them
+pub fn checkout(root: &Path) -> PathBuf {
+    match fs::symlink_metadata(root.join(CURRENT)) {
+        Ok(_) => root.join(CHECKOUT),
+        Err(_) => root.to_owned(),
+    }
+}
+
+/// Get the release that is live, if there is one
+pub async fn current(root: &Path) -> Option<PathBuf> {
+    let target = tokio::fs::read_link(root.join(CURRENT)).await.ok()?;
+    Some(root.join(target))
+}
+
+/// Recursively copy the working tree into the release, leaving out the
+/// repository itself and those of any submodules
+fn copy(source: &Path, destination: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        if entry.file_name() == ".git" {
+            continue;
+        }
+
+        let to = destination.join(entry.file_name());
+        let kind = entry.file_type()?;
+        if kind.is_symlink() {
+            symlink(fs::read_link(entry.path())?, &to)?;
+        } else if kind.is_dir() {
+            fs::create_dir(&to)?;
+            copy(&entry.path(), &to)?;
+        } else {
+            fs::copy(entry.path(), &to)?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,429 @@
+use super::{
+    history::{self, Deployed},
+    message::Changes,
+    Message, Update,
+};
+use crate::{
+    api::Client,
+    config::{
+        self, Credentials, Git, LocalChanges, RepositoryCredentials, RepositoryGit, Secret,
+        Strategy,
+    },
+    lfs,
+    repo::{self, Auth},
+};
+use git2::{Error, ErrorClass, ErrorCode, Oid, Repository};
+use std::path::{Path, PathBuf};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+type Result<T> = std::result::Result<T, git2::Error>;
+
+/// The depth that fetches the entire history of a shallow repository
+const UNSHALLOW: u32 = i32::MAX as u32;
+
+/// Update the local copy of the repository, recording the reference that
+/// was checked out and any local changes, returning whether there is
+/// anything to deploy
+pub async fn update(
+    message: &mut Message,
+    credentials: &Credentials,
+    git: &Git,
+    api: &Client,
+) -> Result<bool> {
+    let path = message.checkout();
+    let id = message.id;
+    let name = message.repository.clone();
+    let options = git.get(&name);
+    let own = credentials.get(&name);
+    let known_hosts = credentials.known_hosts.clone();
+
+    match message.update.clone() {
+        Update::Fetch {
+            clone_url,
+            ssh_url,
+            refspec,
+            commit,
+            changes,
+        } => {
+            message.reference = Some(refspec.clone());
+
+            let url = match ssh_url {
+                Some(ssh_url) if own.use_ssh_url.unwrap_or_default() => ssh_url,
+                _ => clone_url,
+            };
+            let token = token(&own, message.installation, api).await?;
+
+            let (result, local) = tokio::task::spawn_blocking(move || {
+                let auth = auth(&own, &known_hosts, &token);
+                let mut local = Vec::new();
+                let result = fetch(
+                    &path, &name, &url, auth, options, refspec, commit, changes, id, &mut local,
+                );
+                (result, local)
+            })
+            .await
+            .unwrap();
+            message.local_changes = local;
+            result
+        }
+        Update::Revert { tag } => {
+            // Submodules of the previous release may need to be fetched
+            let token = token(&own, message.installation, api).await?;
+
+            let (result, local) = tokio::task::spawn_blocking(move || {
+                let auth = auth(&own, &known_hosts, &token);
+                let mut local = Vec::new();
+                let result = revert(&path, &name, &tag, auth, options, id, &mut local);
+                (result, local)
+            })
+            .await
+            .unwrap();
+            message.local_changes = local;
+            let previous = result?;
+            message.reference = previous.as_ref().map(|t| format!("refs/tags/{}", t));
+            Ok(previous.is_some())
+        }
+        Update::Rollback { to } => {
+            let token = token(&own, message.installation, api).await?;
+
+            let (result, local) = tokio::task::spawn_blocking(move || {
+                let auth = auth(&own, &known_hosts, &token);
+                let mut local = Vec::new();
+                let result = rollback(&path, &name, to.as_deref(), auth, options, id, &mut local);
+                (result, local)
+            })
+            .await
+            .unwrap();
+            message.local_changes = local;
+
+            // Deploy the commit the same way it was originally
+            let deployed = result?;
+            message.reference = deployed.reference;
+            message.environment = deployed.environment;
+            Ok(true)
+        }
+        Update::None => Ok(true),
+    }
+}
+
+/// Get the token for HTTPS remotes, where static tokens take
+/// precedence over the GitHub App
+async fn token(
+    own: &RepositoryCredentials,
+    installation: Option<u64>,
+    api: &Client,
+) -> Result<Option<Secret>> {
+    match (own.token.clone(), installation) {
+        (Some(token), _) => Ok(Some(token)),
+        (None, Some(installation)) if api.has_app() => {
+            let token = api.installation_token(installation).await.map_err(|e| {
+                Error::from_str(&format!("failed to get installation token: {}", e))
+            })?;
+            Ok(Some(token))
+        }
+        (None, _) => Ok(None),
+    }
+}
+
+/// Build the authentication for the remote and any submodules
+fn auth<'a>(
+    own: &'a RepositoryCredentials,
+    known_hosts: &'a Option<PathBuf>,
+    token: &'a Option<Secret>,
+) -> Auth<'a> {
+    Auth {
+        ssh_key: own.ssh_key.as_deref(),
+        known_hosts: known_hosts.as_deref(),
+        token: token.as_ref().map(|t| t.expose()),
+    }
+}
+
+/// Fetch the reference into the local copy of the repository, returning
+/// whether the changes should be deployed
+#[allow(clippy::too_many_arguments)]
+fn fetch(
+    path: &Path,
+    name: &str,
+    url: &str,
+    auth: Auth,
+    options: RepositoryGit,
+    fetch_refspec: String,
+    merge_refspec: Option<String>,
+    changes: Option<Box<Changes>>,
+    id: Uuid,
+    local: &mut Vec<String>,
+) -> Result<bool> {
+    // Initialize the repository
+    let mut repo = Repository::init(path)?;
+
+    // Get the repository's remote to pull
+    repo.remote_set_url("origin", url)?;
+    let mut remote = repo.find_remote("origin").unwrap();
+
+    // Branch and tag names from deployments need to be fully qualified
+    let fetch_refspec = if fetch_refspec.starts_with("refs/") {
+        fetch_refspec
+    } else {
+        repo::resolve_reference(&mut remote, &fetch_refspec, auth)?
+    };
+
+    let first = repo::head_commit(&repo)?.is_none();
+
+    // Download the repository, only limiting the history the first time
+    // as the rest of it is fetched whenever a merge base is needed
+    let depth = options.depth.filter(|_| first);
+    let tags = options.tags.unwrap_or_default();
+    match depth {
+        Some(depth) => info!("pulling {} for {} at depth {}", fetch_refspec, name, depth),
+        None => info!("pulling {} for {}", fetch_refspec, name),
+    }
+    let fetched = repo::fetch(&repo, &[&fetch_refspec], &mut remote, auth, depth, tags)?.id();
+
+    // Move to the pushed commit if there is one, otherwise the head of the reference
+    let target = match &merge_refspec {
+        Some(commit) => Oid::from_str(commit)?,
+        None => fetched,
+    };
+    let strategy = options.strategy.unwrap_or_default();
+
+    // Only deploy a given commit if it's on the reference, as other commits
+    // can be left over from earlier fetches or come from tags
+    if !repo::contains(&repo, fetched, target)? && repo.is_shallow() {
+        info!("fetching the full history of {} to find {}", name, target);
+        repo::fetch(
+            &repo,
+            &[&fetch_refspec],
+            &mut remote,
+            auth,
+            Some(UNSHALLOW),
+            tags,
+        )?;
+    }
+    if !repo::contains(&repo, fetched, target)? {
+        let reference = match fetch_refspec.as_str() {
+            "HEAD" => "the default branch",
+            other => other,
+        };
+        return Err(Error::new(
+            ErrorCode::Unmerged,
+            ErrorClass::Reference,
+            format!("commit {} is not on {}", target, reference),
+        ));
+    }
+
+    // Fetching the commits in between is unnecessary when resetting
+    if strategy != Strategy::Reset && repo::needs_deepening(&repo, target)? {
+        info!(
+            "fetching the full history of {} to find the merge base",
+            name
+        );
+        repo::fetch(
+            &repo,
+            &[&fetch_refspec],
+            &mut remote,
+            auth,
+            Some(UNSHALLOW),
+            tags,
+        )?;
+    }
+
+    // Check the pushed changes before touching the checkout, so pushes that
+    // aren't deployed leave it as it is
+    if let (Some(changes), Some(after)) = (changes, &merge_refspec) {
+        if !changes.skip_markers.is_empty() {
+            let message = repo::commit_message(&repo, after)?;
+            if config::skipped(&message, &changes.skip_markers) {
+                info!("head commit of {} marked to skip deploy", name);
+                return Ok(false);
+            }
+        }
+
+        // Deploy if the changed files cannot be determined
+        if let Some(paths) = changes.paths {
+            if let Some(files) = repo::changed_files(&repo, &changes.before, after)? {
+                if !paths.matches(files) {
+                    info!("push to {} changed no deployed paths", name);
+                    return Ok(false);
+                }
+            }
+        }
+    }
+
+    drop(remote);
+    handle_local_changes(&mut repo, name, &options, id, local)?;
+    info!("updating {} to {} by {:?}", name, target, strategy);
+    repo::update(&repo, &fetch_refspec, target, strategy)?;
+    checkout_lfs_objects(&repo, name, auth, &options)?;
+    update_submodules(&repo, name, auth, &options)?;
+
+    Ok(true)
+}
+
+/// Revert the local copy of the repository to the release before the given tag,
+/// returning the tag reverted to. Only reverts if the tag is the one currently
+/// checked out.
+fn revert(
+    path: &Path,
+    name: &str,
+    tag: &str,
+    auth: Auth,
+    options: RepositoryGit,
+    id: Uuid,
+    local: &mut Vec<String>,
+) -> Result<Option<String>> {
+    // Nothing can be reverted if it was never deployed
+    if !path.exists() {
+        return Ok(None);
+    }
+    let mut repo = Repository::open(path)?;
+
+    if !repo::is_checked_out(&repo, tag)? {
+        info!("release {} of {} is not deployed, not reverting", tag, name);
+        return Ok(None);
+    }
+
+    match repo::previous_tag(&repo, tag)? {
+        Some((previous, commit)) => {
+            info!("reverting {} from {} to {}", name, tag, previous);
+            handle_local_changes(&mut repo, name, &options, id, local)?;
+            repo::checkout(&repo, &commit.to_string())?;
+            checkout_lfs_objects(&repo, name, auth, &options)?;
+            update_submodules(&repo, name, auth, &options)?;
+            Ok(Some(previous))
+        }
+        None => {
+            warn!("no release of {} before {} to revert to", name, tag);
+            Ok(None)
+        }
+    }
+}
+
+/// Check out the commit of an earlier successful deploy, returning that deploy
+fn rollback(
+    path: &Path,
+    name: &str,
+    to: Option<&str>,
+    auth: Auth,
+    options: RepositoryGit,
+    id: Uuid,
+    local: &mut Vec<String>,
+) -> Result<Deployed> {
+    if !path.exists() {
+        return Err(Error::new(
+            ErrorCode::NotFound,
+            ErrorClass::Repository,
+            "checkout does not exist",
+        ));
+    }
+    let mut repo = Repository::open(path)?;
+
+    let history = history::load(&repo)?;
+    let (commit, deployed) = history::find(history, to).ok_or_else(|| {
+        Error::new(
+            ErrorCode::NotFound,
+            ErrorClass::Reference,
+            "no earlier deploy to roll back to",
+        )
+    })?;
+
+    info!("rolling back {} to {}", name, commit);
+    handle_local_changes(&mut repo, name, &options, id, local)?;
+    repo::checkout(&repo, &commit.to_string())?;
+    checkout_lfs_objects(&repo, name, auth, &options)?;
+    update_submodules(&repo, name, auth, &options)?;
+    Ok(deployed)
+}
+
+/// Find the files changed in the checkout outside of deploys, recording them
+/// in the checkout's history and dealing with them according to the policy
+/// before the checkout is updated
+fn handle_local_changes(
+    repo: &mut Repository,
+    name: &str,
+    options: &RepositoryGit,
+    id: Uuid,
+    local: &mut Vec<String>,
+) -> Result<()> {
+    let untracked = options.untracked.unwrap_or_default();
+    *local = repo::local_changes(repo, untracked)?;
+    if local.is_empty() {
+        return Ok(());
+    }
+    warn!(
+        "checkout of {} has local changes to {}",
+        name,
+        local.join(", ")
+    );
+    if let Err(e) = history::record_local_changes(repo, id, local) {
+        error!(error = %e, "failed to record local changes of {}", name);
+    }
+
+    match options.local_changes.unwrap_or_default() {
+        LocalChanges::Abort => {
+            return Err(Error::new(
+                ErrorCode::Modified,
+                ErrorClass::Repository,
+                "checkout has local changes",
+            ))
+        }
+        LocalChanges::Stash => {
+            let reference = repo::stash(repo, untracked)?;
+            info!("saved local changes of {} to {}", name, reference);
+        }
+        LocalChanges::Discard => {
+            repo::discard(repo, untracked)?;
+            info!("discarded local changes of {}", name);
+        }
+    }
+
+    Ok(())
+}
+
+/// Replace the LFS pointers in the checkout with the objects they refer to
+fn checkout_lfs_objects(
+    repo: &Repository,
+    name: &str,
+    auth: Auth,
+    options: &RepositoryGit,
+) -> Result<()> {
+    if !options.lfs.unwrap_or(true) {
+        return Ok(());
+    }
+
+    let remote = repo.find_remote("origin")?;
+    let url = remote.url().unwrap_or_default();
+    match lfs::checkout(repo, url, auth.token) {
+        Ok(0) => Ok(()),
+        Ok(count) => {
+            info!("checked out {} LFS objects for {}", count, name);
+            Ok(())
+        }
+        Err(e) => Err(Error::from_str(&format!(
+            "failed to check out LFS objects: {:#}",
+            e
+        ))),
+    }
+}
+
+/// Update the submodules allowed by the options, failing if any of them could not be
+fn update_submodules(
+    repo: &Repository,
+    name: &str,
+    auth: Auth,
+    options: &RepositoryGit,
+) -> Result<()> {
+    let failed = repo::update_submodules(repo, auth, &|path| options.updates_submodule(path));
+    if failed.is_empty() {
+        return Ok(());
+    }
+
+    for (path, e) in &failed {
+        error!(submodule = %path, "failed to update submodule of {}: {}", name, e.message());
+    }
+    let paths = failed.into_iter().map(|(p, _)| p).collect::<Vec<_>>();
+    Err(Error::from_str(&format!(
+        "failed to update submodules {}",
+        paths.join(", ")
+    )))
+}
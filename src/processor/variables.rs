@@ -0,0 +1,100 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Variables available to the deployment actions, substituted into
+/// them as `${name}` and exposed to commands as environment variables
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Variables(HashMap<String, String>);
+
+impl From<HashMap<String, String>> for Variables {
+    fn from(variables: HashMap<String, String>) -> Self {
+        Self(variables)
+    }
+}
+
+impl Variables {
+    /// Flatten a JSON object into variables, joining nested keys with `.`
+    pub fn from_json(value: &Value) -> Self {
+        let mut variables = Self::default();
+        if let Value::Object(map) = value {
+            for (key, value) in map {
+                variables.flatten(key.clone(), value);
+            }
+        }
+        variables
+    }
+
+    fn flatten(&mut self, key: String, value: &Value) {
+        match value {
+            Value::Object(map) => {
+                for (child, value) in map {
+                    self.flatten(format!("{}.{}", key, child), value);
+                }
+            }
+            Value::String(s) => self.insert(key, s.clone()),
+            Value::Null => self.insert(key, String::new()),
+            other => self.insert(key, other.to_string()),
+        }
+    }
+
+    /// Add or replace a variable
+    pub fn insert(&mut self, key: String, value: String) {
+        self.0.insert(key, value);
+    }
+
+    /// Add the variables from another set, replacing any existing ones
+    pub fn extend(&mut self, other: &Self) {
+        self.0
+            .extend(other.0.iter().map(|(k, v)| (k.clone(), v.clone())));
+    }
+
+    /// Get the value of a variable
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(String::as_str)
+    }
+
+    /// Replace all the `${name}` references in the input with their values,
+    /// leaving references to undefined variables as is
+    pub fn substitute(&self, input: &str) -> String {
+        let mut output = String::with_capacity(input.len());
+        let mut rest = input;
+
+        while let Some(start) = rest.find("${") {
+            output.push_str(&rest[..start]);
+            let reference = &rest[start..];
+
+            match reference.find('}') {
+                Some(end) => {
+                    match self.get(&reference[2..end]) {
+                        Some(value) => output.push_str(value),
+                        None => output.push_str(&reference[..=end]),
+                    }
+                    rest = &reference[end + 1..];
+                }
+                None => {
+                    output.push_str(reference);
+                    rest = "";
+                }
+            }
+        }
+        output.push_str(rest);
+
+        output
+    }
+
+    /// Get the variables as environment variables, prefixed with
+    /// `AUTODEPLOY_` and with any non-alphanumeric characters replaced
+    pub fn environment(&self) -> impl Iterator<Item = (String, &str)> {
+        self.0.iter().map(|(key, value)| {
+            let name = key
+                .chars()
+                .map(|c| match c {
+                    c if c.is_ascii_alphanumeric() => c.to_ascii_uppercase(),
+                    _ => '_',
+                })
+                .collect::<String>();
+            (format!("AUTODEPLOY_{}", name), value.as_str())
+        })
+    }
+}
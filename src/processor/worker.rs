@@ -1,64 +1,304 @@
 use super::{
     config::{Action, Config},
-    Message,
+    history,
+    locks::Locks,
+    pool::Pool,
+    queue::Cancel,
+    release, update, Kind, Message, Queue, Update, Variables,
+};
+use crate::{
+    api::{Client, DeploymentState},
+    config::{Credentials, Git},
 };
 use anyhow::Result;
-use async_channel::Receiver;
-use std::path::Path;
-use tokio::{fs, process::Command};
+use git2::ErrorCode;
+use std::{
+    io, os::unix::process::CommandExt, path::Path, process::Command as StdCommand, sync::Arc,
+};
+use tokio::{
+    fs,
+    process::{Child, Command},
+};
 use tracing::{error, info, instrument};
+use uuid::Uuid;
 
 /// Process incoming deployment workloads
-#[instrument(skip(receiver))]
-pub async fn worker(id: u32, receiver: Receiver<Message>) {
+#[instrument(skip(pool, queue, locks, credentials, git, api))]
+pub async fn worker(
+    id: u32,
+    pool: Arc<Pool>,
+    queue: Queue,
+    locks: Locks,
+    credentials: Arc<Credentials>,
+    git: Arc<Git>,
+    api: Client,
+) {
     info!("started worker {}", id);
 
-    while let Ok(message) = receiver.recv().await {
-        info!("beginning deploy");
+    loop {
+        // Hold the repository until both the update and actions complete
+        let (mut message, lock) = pool.pop(&locks).await;
+        if let Some(cancel) = queue.start(&message).await {
+            process(&api, &credentials, &git, &mut message, cancel).await;
+            queue.finish(&message);
+        }
+
+        drop(lock);
+        pool.done();
+    }
+}
+
+/// Update the repository and run the deployment
+async fn process(
+    api: &Client,
+    credentials: &Credentials,
+    git: &Git,
+    message: &mut Message,
+    mut cancel: Cancel,
+) {
+    info!(id = %message.id, repository = %&message.repository, "beginning deploy");
+
+    // Update the local copy of the repository
+    match update::update(message, credentials, git, api).await {
+        Ok(true) => {}
+        Ok(false) => return,
+        Err(e) => {
+            error!(
+                "error while interacting with local repo: ({:?}, {:?}) {}",
+                e.class(),
+                e.code(),
+                e.message()
+            );
+
+            // The checkout diverging is a problem with the deploy rather than the server
+            let state = match e.code() {
+                ErrorCode::NotFastForward
+                | ErrorCode::Conflict
+                | ErrorCode::Modified
+                | ErrorCode::Unmerged => DeploymentState::Failure,
+                _ => DeploymentState::Error,
+            };
+
+            // Say why the deploy was refused, as it can be fixed by whoever made it
+            match state {
+                DeploymentState::Failure => {
+                    let description = format!("{}: {}", state.description(), e.message());
+                    describe(api, message, state, &description).await;
+                }
+                _ => report(api, message, state).await,
+            }
+            return;
+        }
+    }
+    report(api, message, DeploymentState::InProgress).await;
+
+    // Rolling back to an earlier release only needs the link moved back to it
+    if let (Some(_), Update::Rollback { .. }) = (message.releases, &message.update) {
+        let state = match release::find(&message.path, &message.checkout()).await {
+            Ok(Some(release)) => match release::activate(&message.path, &release).await {
+                Ok(_) => DeploymentState::Success,
+                Err(e) => {
+                    error!(error = %e, "failed to activate release");
+                    DeploymentState::Error
+                }
+            },
+            Ok(None) => {
+                error!("the release to roll back to was removed");
+                DeploymentState::Failure
+            }
+            Err(e) => {
+                error!(error = %e, "failed to find release");
+                DeploymentState::Error
+            }
+        };
+        complete(api, message, state).await;
+        return;
+    }
+
+    // Deploys go into a new release while teardowns run in the live one
+    let release = match (message.releases, message.kind) {
+        (None, _) => None,
+        (Some(_), Kind::Teardown) => release::current(&message.path).await,
+        (Some(_), _) => match release::create(&message.path, &message.checkout()).await {
+            Ok(release) => Some(release),
+            Err(e) => {
+                error!(error = ?e, "failed to create release");
+                report(api, message, DeploymentState::Error).await;
+                return;
+            }
+        },
+    };
+    // Teardowns without a live release run in the checkout instead
+    let directory = release.clone().unwrap_or_else(|| message.checkout());
+
+    // Run the deployment
+    let result = deploy(message, &directory, &mut cancel).await;
+    let mut state = match result {
+        Ok(Outcome::Succeeded) => {
+            info!("deploy successful");
+            DeploymentState::Success
+        }
+        Ok(Outcome::Failed) => {
+            error!("deploy failed");
+            DeploymentState::Failure
+        }
+        Ok(Outcome::Cancelled(by)) => {
+            info!("deploy cancelled (superseded by {})", by);
+            DeploymentState::Inactive
+        }
+        Err(e) => {
+            error!(error = %e, "deploy failed");
+            DeploymentState::Failure
+        }
+    };
+
+    // Only make the release live if every action succeeded
+    if let (Some(release), Some(keep), Kind::Deploy | Kind::Preview) =
+        (release, message.releases, message.kind)
+    {
+        match state {
+            DeploymentState::Success => {
+                if let Err(e) = release::activate(&message.path, &release).await {
+                    error!(error = %e, "failed to activate release");
+                    state = DeploymentState::Error;
+                } else if let Err(e) = release::prune(&message.path, keep).await {
+                    error!(error = %e, "failed to remove old releases");
+                }
+            }
+            _ => release::discard(&release).await,
+        }
+    }
+    complete(api, message, state).await;
+
+    // Remove the preview once it has been torn down
+    if let Kind::Teardown = message.kind {
+        match fs::remove_dir_all(&message.path).await {
+            Ok(_) => info!(path = ?&message.path, "removed preview"),
+            Err(e) => error!(path = ?&message.path, error = %e, "failed to remove preview"),
+        }
+    }
+}
 
-        // Run the deployment
-        let result = deploy(&message.path, &message.repository).await;
-        match result {
-            Ok(true) => info!("deploy successful"),
-            Ok(false) => error!("deploy failed"),
-            Err(e) => error!(error = %e, "deploy failed"),
+/// Record successful deploys in the checkout's history, then report the final state
+async fn complete(api: &Client, message: &Message, state: DeploymentState) {
+    if let (DeploymentState::Success, Kind::Deploy) = (&state, message.kind) {
+        if let Err(e) = history::record(message).await {
+            error!(error = %e, "failed to record deploy");
         }
     }
+    report(api, message, state).await;
+}
+
+/// Report the state of the deployment to GitHub, if it was created through the API
+pub async fn report(api: &Client, message: &Message, state: DeploymentState) {
+    describe(api, message, state, state.description()).await;
+}
+
+/// Report the state of the deployment to GitHub with the given description
+async fn describe(api: &Client, message: &Message, state: DeploymentState, description: &str) {
+    if let Some(id) = message.deployment {
+        let description = if message.local_changes.is_empty() {
+            description.to_string()
+        } else {
+            format!(
+                "{} (local changes: {})",
+                description,
+                message.local_changes.join(", ")
+            )
+        };
+        let result = api
+            .create_deployment_status(&message.repository, id, state, &description)
+            .await;
+        if let Err(e) = result {
+            error!(error = %e, deployment = id, "failed to report deployment status");
+        }
+    }
+}
+
+/// The result of running a deployment's actions
+enum Outcome {
+    Succeeded,
+    Failed,
+    /// Cancelled in favor of the given newer deployment
+    Cancelled(Uuid),
 }
 
 /// Run the deployment process
-#[instrument(skip(path))]
-async fn deploy(path: &Path, repository: &str) -> Result<bool> {
+#[instrument(skip(message, cancel), fields(repository = %message.repository, kind = ?message.kind))]
+async fn deploy(message: &Message, path: &Path, cancel: &mut Cancel) -> Result<Outcome> {
     // Get the deployment configuration
-    let actions = Config::parse(&path.join("autodeploy.toml")).await?;
+    let mut config = Config::parse(&path.join("autodeploy.toml")).await?;
+    let cleanup = config.cleanup();
+    let (actions, variables) = config.resolve(message)?;
     info!("successfully parsed configuration");
 
-    // Run the actions
-    let mut successful = 0;
-    for action in &actions {
+    // Run the actions, cleaning up after them if they were cancelled
+    let outcome = run(path, &actions, &variables, Some(cancel)).await?;
+    if let Outcome::Cancelled(_) = outcome {
+        info!("running cleanup actions");
+        if let Err(e) = run(path, &cleanup, &variables, None).await {
+            error!(error = %e, "cleanup failed");
+        }
+    }
+
+    Ok(outcome)
+}
+
+/// Run the actions in order, stopping at the first failure or once cancelled
+async fn run(
+    path: &Path,
+    actions: &[Action],
+    variables: &Variables,
+    mut cancel: Option<&mut Cancel>,
+) -> Result<Outcome> {
+    for action in actions {
+        // Stop before starting the next action if a newer deployment came in
+        if let Some(by) = cancel.as_deref().and_then(|c| *c.borrow()) {
+            return Ok(Outcome::Cancelled(by));
+        }
+
         match action {
             Action::Command { command, args } => {
+                let command = variables.substitute(command);
+                let args = args
+                    .iter()
+                    .map(|a| variables.substitute(a))
+                    .collect::<Vec<_>>();
                 info!(command = %&command, args = ?&args, "running command");
 
-                // Build the command
-                let mut cmd = Command::new(&command);
+                // Build the command, in its own process group so anything
+                // it starts can be killed along with it
+                let mut cmd = StdCommand::new(&command);
                 cmd.current_dir(path);
-                for arg in args {
-                    cmd.arg(arg);
-                }
+                cmd.args(&args);
+                cmd.envs(variables.environment());
+                cmd.process_group(0);
+
+                // Wait for the command to exit, killing it if cancelled
+                let mut child = Command::from(cmd).spawn()?;
+                let status = match cancel.as_deref_mut() {
+                    Some(cancel) => tokio::select! {
+                        status = child.wait() => status?,
+                        Ok(()) = cancel.changed() => {
+                            kill_group(&mut child).await?;
+                            info!(command = %&command, "command killed");
 
-                // Get the output of the command
-                let status = cmd.status().await?;
+                            let by = cancel.borrow().unwrap_or_default();
+                            return Ok(Outcome::Cancelled(by));
+                        }
+                    },
+                    None => child.wait().await?,
+                };
                 if status.success() {
                     info!(command = %&command, "command succeeded");
                 } else {
                     error!(command = %&command, code = %status, "command failed");
-                    break;
+                    return Ok(Outcome::Failed);
                 }
-
-                successful += 1;
             }
             Action::Copy { src, dest } => {
+                let src = variables.substitute(src);
+                let dest = variables.substitute(dest);
                 info!(src = ?&src, dest = ?&dest, "copying file");
 
                 // Copy the file
@@ -67,13 +307,24 @@ async fn deploy(path: &Path, repository: &str) -> Result<bool> {
                 // Check for errors
                 if let Err(e) = result {
                     error!(src = ?&src, dest = ?&dest, error = %e, "failed to copy file");
-                    break;
+                    return Ok(Outcome::Failed);
                 }
-
-                successful += 1;
             }
         }
     }
 
-    Ok(successful == actions.len())
+    Ok(Outcome::Succeeded)
+}
+
+/// Kill the command and every process in its group, waiting for it to exit
+async fn kill_group(child: &mut Child) -> io::Result<()> {
+    if let Some(pid) = child.id() {
+        // A negative pid sends the signal to the whole group
+        if unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGKILL) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    child.wait().await?;
+    Ok(())
 }
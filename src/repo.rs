@@ -1,10 +1,22 @@
+use crate::{
+    config::{Strategy, Tags},
+    lfs,
+};
+use chrono::Utc;
 use git2::{
-    build::CheckoutBuilder, AnnotatedCommit, AutotagOption, FetchOptions, Oid, Reference, Remote,
-    RemoteCallbacks, Repository, ResetType,
+    build::CheckoutBuilder, cert::Cert, AnnotatedCommit, AutotagOption, CertificateCheckStatus,
+    Commit, Cred, CredentialType, Direction, Error, ErrorClass, ErrorCode, FetchOptions, Oid,
+    Remote, RemoteCallbacks, Repository, ResetType, Signature, StashFlags, Status, StatusEntry,
+    StatusOptions, Submodule, SubmoduleUpdateOptions,
+};
+use ring::{
+    digest::{digest, SHA256},
+    hmac,
 };
+use std::{fs, path::Path};
 use tracing::{debug, error, info};
 
-type Result<T> = std::result::Result<T, git2::Error>;
+type Result<T> = std::result::Result<T, Error>;
 
 /// Checkout the specified commit by SHA1 hash
 pub fn checkout(repo: &Repository, hash: &str) -> Result<()> {
@@ -22,14 +34,411 @@ pub fn checkout(repo: &Repository, hash: &str) -> Result<()> {
     Ok(())
 }
 
-/// Fetch all the data in the given refspec
+/// Check if the commit pointed to by the tag is the one currently checked out
+pub fn is_checked_out(repo: &Repository, tag: &str) -> Result<bool> {
+    let head = repo.head()?.peel_to_commit()?;
+
+    match tag_commit(repo, tag) {
+        Ok(commit) => Ok(commit.id() == head.id()),
+        Err(e) if e.code() == ErrorCode::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Find the most recent tag in the history of the given tag, excluding
+/// any that point to the same commit
+pub fn previous_tag(repo: &Repository, tag: &str) -> Result<Option<(String, Oid)>> {
+    let current = tag_commit(repo, tag)?;
+
+    let mut previous: Option<(&str, Commit)> = None;
+    let names = repo.tag_names(None)?;
+    for name in names.iter().flatten() {
+        // Ignore tags that don't point to commits
+        let commit = match tag_commit(repo, name) {
+            Ok(c) => c,
+            Err(_) => continue,
+        };
+
+        // Only consider tags that come before the current one
+        if commit.id() == current.id() || !repo.graph_descendant_of(current.id(), commit.id())? {
+            continue;
+        }
+
+        let newer = match &previous {
+            Some((_, p)) => commit.time().seconds() > p.time().seconds(),
+            None => true,
+        };
+        if newer {
+            previous = Some((name, commit));
+        }
+    }
+
+    Ok(previous.map(|(name, commit)| (name.to_string(), commit.id())))
+}
+
+/// Get the commit a tag points to
+fn tag_commit<'r>(repo: &'r Repository, tag: &str) -> Result<Commit<'r>> {
+    repo.find_reference(&format!("refs/tags/{}", tag))?
+        .peel_to_commit()
+}
+
+/// Get the message of the commit by SHA1 hash
+pub fn commit_message(repo: &Repository, hash: &str) -> Result<String> {
+    let commit = repo.find_commit(Oid::from_str(hash)?)?;
+    Ok(String::from_utf8_lossy(commit.message_bytes()).into_owned())
+}
+
+/// Get the paths of the files changed between two commits, if the
+/// starting commit exists locally
+pub fn changed_files(repo: &Repository, before: &str, after: &str) -> Result<Option<Vec<String>>> {
+    let old = match Oid::from_str(before).and_then(|oid| repo.find_commit(oid)) {
+        Ok(c) => c,
+        Err(_) => return Ok(None),
+    };
+    let new = repo.find_commit(Oid::from_str(after)?)?;
+
+    let diff = repo.diff_tree_to_tree(Some(&old.tree()?), Some(&new.tree()?), None)?;
+    let files = diff
+        .deltas()
+        .flat_map(|d| vec![d.old_file().path(), d.new_file().path()])
+        .flatten()
+        .filter_map(|p| p.to_str())
+        .map(String::from)
+        .collect();
+
+    Ok(Some(files))
+}
+
+/// Find the files changed in the working tree since the last checkout,
+/// optionally including untracked files but never ignored ones. LFS pointers
+/// replaced by the objects they refer to are not considered changed.
+pub fn local_changes(repo: &Repository, untracked: bool) -> Result<Vec<String>> {
+    // Everything would be untracked before the first checkout
+    if head_commit(repo)?.is_none() {
+        return Ok(Vec::new());
+    }
+
+    let statuses = repo.statuses(Some(
+        StatusOptions::new()
+            .include_untracked(untracked)
+            .recurse_untracked_dirs(untracked)
+            .exclude_submodules(true),
+    ))?;
+    let files = statuses
+        .iter()
+        .filter(|s| !s.status().is_empty() && !s.status().contains(Status::IGNORED))
+        .filter(|s| !is_lfs_object(repo, s))
+        .map(|s| String::from_utf8_lossy(s.path_bytes()).into_owned())
+        .collect();
+
+    Ok(files)
+}
+
+/// Check if the only change to the file is replacing its LFS pointer
+fn is_lfs_object(repo: &Repository, entry: &StatusEntry) -> bool {
+    if entry.status() != Status::WT_MODIFIED {
+        return false;
+    }
+
+    let (delta, workdir) = match (entry.index_to_workdir(), repo.workdir()) {
+        (Some(d), Some(w)) => (d, w),
+        _ => return false,
+    };
+    let pointer = match repo.find_blob(delta.old_file().id()) {
+        Ok(blob) => lfs::Pointer::parse(blob.content()),
+        Err(_) => None,
+    };
+
+    match (pointer, delta.new_file().path()) {
+        (Some(pointer), Some(path)) => pointer.matches(&workdir.join(path)),
+        _ => false,
+    }
+}
+
+/// Save the local changes to a timestamped reference so they can be
+/// recovered, returning the name of the reference
+pub fn stash(repo: &mut Repository, untracked: bool) -> Result<String> {
+    let signature = signature(repo)?;
+    let flags = match untracked {
+        true => StashFlags::INCLUDE_UNTRACKED,
+        false => StashFlags::DEFAULT,
+    };
+    let stash = repo.stash_save(&signature, "autodeploy: local changes", Some(flags))?;
+
+    // Keep the changes out of the stash list where they could be lost
+    let name = format!(
+        "refs/autodeploy/changes/{}",
+        Utc::now().format("%Y%m%dT%H%M%S%.3fZ")
+    );
+    repo.reference(&name, stash, false, "autodeploy: saving local changes")?;
+    repo.stash_drop(0)?;
+
+    Ok(name)
+}
+
+/// Throw away the local changes, optionally including untracked files
+pub fn discard(repo: &Repository, untracked: bool) -> Result<()> {
+    let head = repo.head()?.peel_to_commit()?;
+    repo.reset(head.as_object(), ResetType::Hard, None)?;
+    repo.checkout_head(Some(
+        CheckoutBuilder::default()
+            .force()
+            .remove_untracked(untracked),
+    ))
+}
+
+/// Point the reference at the commit, keeping every commit it pointed at in its log
+pub fn log_reference(repo: &Repository, name: &str, commit: Oid, message: &str) -> Result<()> {
+    repo.reference_ensure_log(name)?;
+
+    // Updating the reference to the commit it already points at isn't logged
+    if repo.refname_to_id(name).ok() == Some(commit) {
+        let mut reflog = repo.reflog(name)?;
+        reflog.append(commit, &signature(repo)?, Some(message))?;
+        reflog.write()?;
+    } else {
+        repo.reference(name, commit, true, message)?;
+    }
+    Ok(())
+}
+
+/// Get the commits the reference pointed at with their log messages, newest first
+pub fn reference_log(repo: &Repository, name: &str) -> Result<Vec<(Oid, String)>> {
+    let reflog = repo.reflog(name)?;
+    let entries = reflog
+        .iter()
+        .map(|e| (e.id_new(), e.message().unwrap_or_default().to_string()))
+        .collect();
+    Ok(entries)
+}
+
+/// How to authenticate with the remote
+#[derive(Clone, Copy, Default)]
+pub struct Auth<'a> {
+    /// The private key for SSH remotes
+    pub ssh_key: Option<&'a Path>,
+    /// The file to check the host keys of SSH remotes against
+    pub known_hosts: Option<&'a Path>,
+    /// The token for HTTPS remotes
+    pub token: Option<&'a str>,
+}
+
+/// Build the callbacks to authenticate with the remote
+fn callbacks<'a>(url: &str, auth: Auth<'a>) -> RemoteCallbacks<'a> {
+    let mut callbacks = RemoteCallbacks::new();
+
+    let mut attempted = false;
+    callbacks.credentials(move |_url, username, allowed| {
+        let username = username.unwrap_or("git");
+        if allowed.contains(CredentialType::USERNAME) {
+            return Cred::username(username);
+        }
+
+        // Give up instead of retrying the same credentials forever
+        if attempted {
+            return Err(git2::Error::from_str("authentication failed"));
+        }
+        attempted = true;
+
+        match (auth.ssh_key, auth.token) {
+            (Some(key), _) if allowed.contains(CredentialType::SSH_KEY) => {
+                Cred::ssh_key(username, None, key, None)
+            }
+            (_, Some(token)) if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) => {
+                Cred::userpass_plaintext("x-access-token", token)
+            }
+            _ => Err(git2::Error::from_str(
+                "no credentials configured for remote",
+            )),
+        }
+    });
+
+    // Setting the callback overrides certificate validation entirely,
+    // so only use it for SSH host keys
+    if is_ssh(url) {
+        callbacks.certificate_check(move |cert, host| {
+            let trusted = match auth.known_hosts {
+                Some(path) => is_known_host(path, host, cert),
+                None => {
+                    error!(
+                        "no known_hosts file configured, rejecting host key of {}",
+                        host
+                    );
+                    false
+                }
+            };
+
+            if trusted {
+                Ok(CertificateCheckStatus::CertificateOk)
+            } else {
+                Err(Error::new(
+                    ErrorCode::Certificate,
+                    ErrorClass::Ssh,
+                    format!("untrusted host key for {}", host),
+                ))
+            }
+        });
+    }
+
+    callbacks
+}
+
+/// Check if the URL is for an SSH remote, either `ssh://` or `user@host:path`
+fn is_ssh(url: &str) -> bool {
+    url.starts_with("ssh://") || (!url.contains("://") && url.contains(':'))
+}
+
+/// Get the host of a remote, written either as a URL or in the scp-like
+/// `user@host:path` form, or `None` for local paths
+pub fn remote_host(url: &str) -> Option<String> {
+    let authority = match url.split_once("://") {
+        Some((_, rest)) => rest.split('/').next()?,
+        None => url.split_once(':')?.0,
+    };
+    let host = authority.rsplit_once('@').map_or(authority, |(_, h)| h);
+    let host = match host.strip_prefix('[') {
+        Some(ipv6) => ipv6.split(']').next()?,
+        None => host.split(':').next()?,
+    };
+
+    match host.is_empty() {
+        true => None,
+        false => Some(host.to_ascii_lowercase()),
+    }
+}
+
+/// Check the host key against the known_hosts file, supporting hashed
+/// host names and revoked keys
+fn is_known_host(path: &Path, host: &str, cert: &Cert) -> bool {
+    let fingerprint = match cert.as_hostkey().and_then(|k| k.hash_sha256()) {
+        Some(f) => f,
+        None => {
+            error!("host key of {} has no SHA256 fingerprint", host);
+            return false;
+        }
+    };
+    let contents = match fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(e) => {
+            error!(path = ?path, error = %e, "failed to read known_hosts");
+            return false;
+        }
+    };
+
+    let mut known = false;
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        // Lines are `[@marker] <hosts> <type> <key> [comment]`
+        let mut fields = line.split_whitespace();
+        let (marker, hosts) = match fields.next() {
+            Some(m) if m.starts_with('@') => (Some(m), fields.next()),
+            hosts => (None, hosts),
+        };
+        let (hosts, key) = match (hosts, fields.nth(1)) {
+            (Some(h), Some(k)) => (h, k),
+            _ => continue,
+        };
+        if !matches_host(hosts, host) {
+            continue;
+        }
+
+        let key = match base64::decode(key) {
+            Ok(k) => k,
+            Err(_) => continue,
+        };
+        if digest(&SHA256, &key).as_ref() != fingerprint {
+            continue;
+        }
+
+        match marker {
+            Some("@revoked") => {
+                error!("host key of {} has been revoked", host);
+                return false;
+            }
+            // Certificate authorities are not supported
+            Some(_) => continue,
+            None => known = true,
+        }
+    }
+
+    if !known {
+        error!("host key of {} is not in known_hosts", host);
+    }
+    known
+}
+
+/// Check if the host matches one of the comma separated known_hosts patterns
+fn matches_host(patterns: &str, host: &str) -> bool {
+    patterns.split(',').any(|pattern| {
+        // Hashed entries are `|1|<salt>|<HMAC-SHA1 of the host>`
+        if let Some(hashed) = pattern.strip_prefix("|1|") {
+            let (salt, hash) = match hashed.split_once('|') {
+                Some(parts) => parts,
+                None => return false,
+            };
+            match (base64::decode(salt), base64::decode(hash)) {
+                (Ok(salt), Ok(hash)) => {
+                    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, &salt);
+                    hmac::verify(&key, host.as_bytes(), &hash).is_ok()
+                }
+                _ => false,
+            }
+        } else {
+            pattern.eq_ignore_ascii_case(host)
+                || pattern.eq_ignore_ascii_case(&format!("[{}]:22", host))
+        }
+    })
+}
+
+/// Find the fully qualified name of a branch or tag on the remote, where
+/// `HEAD` is the remote's default branch
+pub fn resolve_reference(remote: &mut Remote, name: &str, auth: Auth) -> Result<String> {
+    if name == "HEAD" {
+        return Ok(name.into());
+    }
+
+    let url = remote.url().unwrap_or_default().to_string();
+    let connection = remote.connect_auth(Direction::Fetch, Some(callbacks(&url, auth)), None)?;
+    let heads = connection
+        .list()?
+        .iter()
+        .map(|h| h.name().to_string())
+        .collect::<Vec<_>>();
+    drop(connection);
+
+    let candidates = [
+        format!("refs/heads/{}", name),
+        format!("refs/tags/{}", name),
+    ];
+    candidates
+        .iter()
+        .find(|c| heads.contains(c))
+        .cloned()
+        .ok_or_else(|| {
+            Error::new(
+                ErrorCode::NotFound,
+                ErrorClass::Reference,
+                format!("no branch or tag named {} on the remote", name),
+            )
+        })
+}
+
+/// Fetch all the data in the given refspec, optionally limiting the
+/// history to the given number of commits
 pub fn fetch<'r>(
     repo: &'r Repository,
     refs: &[&str],
     remote: &'r mut Remote,
+    auth: Auth,
+    depth: Option<u32>,
+    tags: Tags,
 ) -> Result<AnnotatedCommit<'r>> {
     // Log transfer progress
-    let mut callback = RemoteCallbacks::new();
+    let url = remote.url().unwrap_or_default().to_string();
+    let mut callback = callbacks(&url, auth);
     callback.transfer_progress(|stats| {
         if stats.received_objects() == stats.total_objects() {
             debug!(
@@ -49,23 +458,41 @@ pub fn fetch<'r>(
         true
     });
 
-    // Fetch from the remote
-    // Always fetch tags
-    remote.fetch(
-        refs,
-        Some(
-            FetchOptions::new()
-                .remote_callbacks(callback)
-                .download_tags(AutotagOption::All),
+    // Deployed tags need a destination to be kept when others aren't downloaded
+    let (refs, autotag) = match tags {
+        Tags::All => (
+            refs.iter().map(|r| r.to_string()).collect(),
+            AutotagOption::All,
         ),
-        None,
-    )?;
+        Tags::None => (
+            refs.iter().map(|r| r.to_string()).collect(),
+            AutotagOption::None,
+        ),
+        Tags::Reference => {
+            let refs = refs
+                .iter()
+                .map(|r| match r.starts_with("refs/tags/") {
+                    true => format!("+{}:{}", r, r),
+                    false => r.to_string(),
+                })
+                .collect::<Vec<_>>();
+            (refs, AutotagOption::None)
+        }
+    };
+
+    // Fetch from the remote
+    let mut options = FetchOptions::new();
+    options.remote_callbacks(callback).download_tags(autotag);
+    if let Some(depth) = depth {
+        options.depth(depth.min(i32::MAX as u32) as i32);
+    }
+    remote.fetch(&refs, Some(&mut options), None)?;
 
     // Log the stats of the fetch
     let stats = remote.stats();
     if stats.local_objects() > 0 {
         info!(
-            "received {}/{} objects in {} bytes (used {} local objects",
+            "received {}/{} objects in {} bytes (used {} local objects)",
             stats.indexed_objects(),
             stats.total_objects(),
             stats.received_bytes(),
@@ -80,126 +507,259 @@ pub fn fetch<'r>(
         );
     }
 
-    let fetch_head = repo.find_reference("FETCH_HEAD")?;
-    repo.reference_to_annotated_commit(&fetch_head)
-}
-
-/// Merge the pulled branch and the current history
-/// Supports normal merge and fast-forwarding, but will not try to resolve conflicts.
-pub fn merge(repo: &Repository, refname: &str, fetch_commit: AnnotatedCommit) -> Result<()> {
-    // Run a merge analysis
-    let analysis = repo.merge_analysis(&[&fetch_commit])?;
-
-    // Do the appropriate merge (fast-forward/normal/none)
-    if analysis.0.is_fast_forward() {
-        info!("merging with fast-forward");
-
-        match repo.find_reference(&refname) {
-            Ok(mut r) => fast_forward(repo, &mut r, &fetch_commit)?,
-            Err(_) => {
-                // Set reference to commit directly
-                repo.reference(
-                    &refname,
-                    fetch_commit.id(),
-                    true,
-                    &format!("setting {} to {}", refname, fetch_commit.id()),
-                )?;
-                repo.set_head(&refname)?;
-
-                // Checkout the head
-                repo.checkout_head(Some(
-                    CheckoutBuilder::default()
-                        .allow_conflicts(true)
-                        .conflict_style_merge(true)
-                        .force(),
-                ))?;
+    // Downloaded tags are also listed in FETCH_HEAD, so only take the entry
+    // marked for merge, which is the requested ref. It is read directly as
+    // libgit2 gives the entry for a remote's HEAD no name.
+    let fetch_head = fs::read_to_string(repo.path().join("FETCH_HEAD"))
+        .map_err(|e| Error::from_str(&format!("failed to read FETCH_HEAD: {}", e)))?;
+    let fetched = fetch_head.lines().find_map(|line| {
+        let mut fields = line.split('\t');
+        match (fields.next(), fields.next()) {
+            (Some(id), Some("")) => Oid::from_str(id).ok(),
+            _ => None,
+        }
+    });
+    let id = fetched.ok_or_else(|| {
+        Error::new(
+            ErrorCode::NotFound,
+            ErrorClass::Reference,
+            "nothing was fetched for the requested ref",
+        )
+    })?;
+    repo.find_annotated_commit(id)
+}
+
+/// Recursively initialize, sync and update the submodules whose paths are
+/// allowed by the filter, returning the path of each one that failed along
+/// with the reason. Submodules of a failed submodule are not updated.
+pub fn update_submodules(
+    repo: &Repository,
+    auth: Auth,
+    filter: &dyn Fn(&str) -> bool,
+) -> Vec<(String, Error)> {
+    // Credentials only belong to the repository's own host
+    let host = repo
+        .find_remote("origin")
+        .ok()
+        .and_then(|r| r.url().and_then(remote_host));
+
+    let mut failed = Vec::new();
+    update_nested_submodules(
+        repo,
+        Path::new(""),
+        auth,
+        host.as_deref(),
+        filter,
+        &mut failed,
+    );
+    failed
+}
+
+/// Update the submodules of a repository nested at the given path
+fn update_nested_submodules(
+    repo: &Repository,
+    prefix: &Path,
+    auth: Auth,
+    host: Option<&str>,
+    filter: &dyn Fn(&str) -> bool,
+    failed: &mut Vec<(String, Error)>,
+) {
+    let submodules = match repo.submodules() {
+        Ok(s) => s,
+        Err(e) => {
+            failed.push((prefix.display().to_string(), e));
+            return;
+        }
+    };
+
+    for mut submodule in submodules {
+        let path = prefix.join(submodule.path());
+        let name = path.to_string_lossy().into_owned();
+        if !filter(&name) {
+            debug!("skipping submodule {}", name);
+            continue;
+        }
+
+        info!("updating submodule {}", name);
+        match update_submodule(repo, &mut submodule, auth, host) {
+            Ok(child) => update_nested_submodules(&child, &path, auth, host, filter, failed),
+            Err(e) => failed.push((name, e)),
+        }
+    }
+}
+
+/// Initialize, sync and update a single submodule, returning its repository.
+/// Submodules on a different host than the repository are fetched anonymously.
+fn update_submodule(
+    repo: &Repository,
+    submodule: &mut Submodule,
+    auth: Auth,
+    host: Option<&str>,
+) -> Result<Repository> {
+    submodule.init(false)?;
+    submodule.sync()?;
+
+    // Relative URLs are only resolved against the parent's remote once synced
+    let key = format!("submodule.{}.url", submodule.name().unwrap_or_default());
+    let url = repo
+        .config()?
+        .get_string(&key)
+        .unwrap_or_else(|_| submodule.url().unwrap_or_default().to_string());
+
+    let auth = match remote_host(&url) {
+        Some(other) if Some(other.as_str()) == host => auth,
+        _ => {
+            debug!("fetching submodule from {} without credentials", url);
+            Auth {
+                known_hosts: auth.known_hosts,
+                ..Auth::default()
             }
         }
-    } else if analysis.0.is_normal() {
-        info!("merging normally");
+    };
 
-        let head_commit = repo.reference_to_annotated_commit(&repo.head()?)?;
-        normal_merge(&repo, &head_commit, &fetch_commit)?;
-    } else {
-        info!("no merge necessary");
+    let mut fetch = FetchOptions::new();
+    fetch.remote_callbacks(callbacks(&url, auth));
+    submodule.update(true, Some(SubmoduleUpdateOptions::new().fetch(fetch)))?;
+
+    submodule.open()
+}
+
+/// Check if the commit is the fetched one or part of its history
+pub fn contains(repo: &Repository, fetched: Oid, commit: Oid) -> Result<bool> {
+    if commit == fetched {
+        return Ok(true);
     }
 
-    Ok(())
+    // Commits that were never downloaded can't be part of the history
+    match repo.find_commit(commit) {
+        Ok(_) => repo.graph_descendant_of(fetched, commit),
+        Err(e) if e.code() == ErrorCode::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
 }
 
-/// Perform a fast forward merge
-fn fast_forward(
-    repo: &Repository,
-    local_branch: &mut Reference,
-    remote_commit: &AnnotatedCommit,
-) -> Result<()> {
-    // Get the name of the branch
-    let name = match local_branch.name() {
-        Some(s) => s.to_string(),
-        None => String::from_utf8_lossy(local_branch.name_bytes()).to_string(),
+/// Check if the history of a shallow repository is too short to find where
+/// the commit and the one currently checked out diverged
+pub fn needs_deepening(repo: &Repository, target: Oid) -> Result<bool> {
+    let current = match head_commit(repo)? {
+        Some(c) if repo.is_shallow() && c != target => c,
+        _ => return Ok(false),
     };
 
-    // Re-target the current branch
-    local_branch.set_target(
-        remote_commit.id(),
-        &format!(
-            "Fast-forward: setting {} to id {}",
-            name,
-            remote_commit.id()
-        ),
-    )?;
+    match repo.merge_base(current, target) {
+        Ok(_) => Ok(false),
+        Err(e) if e.code() == ErrorCode::NotFound => Ok(true),
+        Err(e) => Err(e),
+    }
+}
 
-    // Set the head
-    repo.set_head(&name)?;
-    repo.checkout_head(Some(CheckoutBuilder::default().force()))?;
+/// Move the checkout to the fetched commit using the strategy, never leaving
+/// conflicts in the working tree. Branches are updated to point to the result,
+/// anything else (tags, pull requests) is checked out as a detached head.
+pub fn update(repo: &Repository, refname: &str, target: Oid, strategy: Strategy) -> Result<()> {
+    let result = match (strategy, head_commit(repo)?) {
+        (_, None) => target,
+        (Strategy::Reset, Some(current)) => {
+            if current != target && !repo.graph_descendant_of(target, current)? {
+                info!("discarding history diverged from {}", target);
+            }
+            target
+        }
+        (_, Some(current)) if current == target || repo.graph_descendant_of(target, current)? => {
+            info!("fast-forwarding from {} to {}", current, target);
+            target
+        }
+        (Strategy::FastForward, Some(current)) => {
+            return Err(Error::new(
+                ErrorCode::NotFastForward,
+                ErrorClass::Reference,
+                format!("cannot fast-forward from {} to {}", current, target),
+            ));
+        }
+        (Strategy::Merge, Some(current)) if repo.graph_descendant_of(current, target)? => {
+            info!("{} is already merged", target);
+            current
+        }
+        (Strategy::Merge, Some(current)) => merge(repo, current, target)?,
+    };
 
-    info!(
-        "successfully fast-forwarded {} to {}",
-        name,
-        remote_commit.id()
-    );
+    // Point the branch or head at the result and make the working tree match
+    let commit = repo.find_commit(result)?;
+    if refname.starts_with("refs/heads/") {
+        repo.reference(
+            refname,
+            result,
+            true,
+            &format!("autodeploy: setting {} to {}", refname, result),
+        )?;
+        repo.set_head(refname)?;
+    } else {
+        repo.set_head_detached(result)?;
+    }
+    repo.reset(
+        commit.as_object(),
+        ResetType::Hard,
+        Some(CheckoutBuilder::default().force()),
+    )?;
 
     Ok(())
 }
 
-/// Perform a normal merge
-fn normal_merge(
-    repo: &Repository,
-    local: &AnnotatedCommit,
-    remote: &AnnotatedCommit,
-) -> Result<()> {
-    // Find the common ancestor between the two commits
-    let local_tree = repo.find_commit(local.id())?.tree()?;
-    let remote_tree = repo.find_commit(remote.id())?.tree()?;
-    let ancestor = repo
-        .find_commit(repo.merge_base(local.id(), remote.id())?)?
-        .tree()?;
-
-    // Merge the two trees
-    let mut index = repo.merge_trees(&ancestor, &local_tree, &remote_tree, None)?;
-
-    // Don't attempt to resolve conflicts
+/// Merge the fetched commit into the current one, returning the merge commit.
+/// Fails rather than trying to resolve conflicts.
+fn merge(repo: &Repository, current: Oid, target: Oid) -> Result<Oid> {
+    let local = repo.find_commit(current)?;
+    let remote = repo.find_commit(target)?;
+
+    let mut index = repo.merge_commits(&local, &remote, None)?;
     if index.has_conflicts() {
-        error!("merge conflicts detected, cannot resolve automatically");
-        repo.checkout_index(Some(&mut index), None)?;
-        return Ok(());
-    }
-
-    // Finalize the merge
-    let result_tree = repo.find_tree(index.write_tree_to(repo)?)?;
-    let signature = repo.signature()?;
-    let local_commit = repo.find_commit(local.id())?;
-    let remote_commit = repo.find_commit(remote.id())?;
-    repo.commit(
-        Some("HEAD"),
+        let paths = index
+            .conflicts()?
+            .filter_map(|c| c.ok())
+            .filter_map(|c| c.our.or(c.their).or(c.ancestor))
+            .map(|e| String::from_utf8_lossy(&e.path).into_owned())
+            .collect::<Vec<_>>();
+        return Err(Error::new(
+            ErrorCode::Conflict,
+            ErrorClass::Merge,
+            format!(
+                "merging {} into {} conflicts in {}",
+                target,
+                current,
+                paths.join(", ")
+            ),
+        ));
+    }
+
+    // Commit the merge without touching the working tree
+    let tree = repo.find_tree(index.write_tree_to(repo)?)?;
+    let signature = signature(repo)?;
+    let merged = repo.commit(
+        None,
         &signature,
         &signature,
-        &format!("Merge: {} into {}", remote.id(), local.id()),
-        &result_tree,
-        &[&local_commit, &remote_commit],
+        &format!("Merge: {} into {}", target, current),
+        &tree,
+        &[&local, &remote],
     )?;
 
-    info!("successfully merged from {} to {}", remote.id(), local.id());
+    info!("successfully merged {} into {}", target, current);
+    Ok(merged)
+}
+
+/// Find the commit currently checked out, if any
+pub fn head_commit(repo: &Repository) -> Result<Option<Oid>> {
+    match repo.head() {
+        Ok(head) => Ok(Some(head.peel_to_commit()?.id())),
+        Err(e) if e.code() == ErrorCode::UnbornBranch || e.code() == ErrorCode::NotFound => {
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
 
-    Ok(())
+/// The identity to record commits with, from the git configuration if there is one
+fn signature(repo: &Repository) -> Result<Signature<'static>> {
+    repo.signature()
+        .or_else(|_| Signature::now("autodeploy", "autodeploy@localhost"))
 }
@@ -0,0 +1,51 @@
+use anyhow::{bail, Result};
+use reqwest::Client;
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use uuid::Uuid;
+
+/// The rollback to ask the server for
+#[derive(Serialize)]
+pub struct Request {
+    pub repository: String,
+    /// When the request was made, so it can't be replayed later
+    pub timestamp: i64,
+    pub target: String,
+    pub to: Option<String>,
+}
+
+/// Ask the running server to roll back the checkout, signing the request
+/// the same way GitHub signs webhooks
+pub async fn run(address: SocketAddr, secret: &str, request: &Request) -> Result<()> {
+    #[derive(Deserialize)]
+    struct Queued {
+        id: Uuid,
+    }
+
+    let body = serde_json::to_vec(request)?;
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    let signature = hex::encode(hmac::sign(&key, &body).as_ref());
+
+    let response = Client::new()
+        .post(format!(
+            "http://{}/repos/{}/rollback",
+            address, request.repository
+        ))
+        .header("X-Hub-Signature-256", format!("sha256={}", signature))
+        .body(body)
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        bail!(
+            "failed to roll back {} ({}): {}",
+            request.repository,
+            response.status(),
+            response.text().await?
+        );
+    }
+
+    let queued: Queued = response.json().await?;
+    println!("Queued rollback {} of {}", queued.id, request.repository);
+    Ok(())
+}